@@ -0,0 +1,158 @@
+// The helper's config file. It is a small part of TOML: `key = value` lines with strings, integers,
+// booleans and lists of strings, and `#` comments. Tables and everything else TOML has are
+// refused, so a file which parses here means the same to any TOML reader.
+
+use std::fs;
+
+pub enum Value {
+    Text(String),
+    Integer(u64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+/// The keys and values in the file at `path`, in order. Dashes and underscores in keys are the
+/// same, they are returned with dashes.
+pub fn read(path: &str) -> Vec<(String, Value)> {
+    let text = fs::read_to_string(path).expect("unable to read config file");
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let fail = |what: &str| -> ! { panic!("{}:{}: {}", path, i + 1, what) };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            fail("expected key = value")
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            fail("invalid key");
+        }
+        let value = value.trim();
+        let value = if let Some(items) = value.strip_prefix('[') {
+            let Some(items) = items.strip_suffix(']') else {
+                fail("lists have to end on the same line")
+            };
+            let items = items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| string(item).unwrap_or_else(|| fail("lists can only hold strings")))
+                .collect();
+            Value::List(items)
+        } else if let Some(text) = string(value) {
+            Value::Text(text)
+        } else if value == "true" || value == "false" {
+            Value::Bool(value == "true")
+        } else if let Ok(n) = value.replace('_', "").parse() {
+            Value::Integer(n)
+        } else {
+            fail("expected a string, integer, boolean or list of strings")
+        };
+        entries.push((key.replace('_', "-"), value));
+    }
+    entries
+}
+
+/// A quoted string, without escapes.
+fn string(value: &str) -> Option<String> {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            if !inner.contains(quote) && !inner.contains('\\') {
+                return Some(inner.to_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Cuts off a `#` comment, unless the `#` is in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('#', None) => return &line[..i],
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => (),
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// Writes `text` to a file of its own and reads it.
+    fn parse(name: &str, text: &str) -> Vec<(String, Value)> {
+        let path = env::temp_dir().join(format!("qft-config-{}-{}.toml", process::id(), name));
+        fs::write(&path, text).unwrap();
+        let entries = read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        entries
+    }
+
+    #[test]
+    fn values() {
+        let entries = parse(
+            "values",
+            "# a helper\n\
+             port = 4277\n\
+             max_waiting = 10_000\n\
+             log = \"/var/log/qft # not a comment\" # a comment\n\
+             stun = false\n\
+             \n\
+             bind = ['0.0.0.0:4277', \"[::]:4277\",]\n",
+        );
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["port", "max-waiting", "log", "stun", "bind"]);
+        assert!(matches!(entries[0].1, Value::Integer(4277)));
+        assert!(matches!(entries[1].1, Value::Integer(10_000)));
+        assert!(matches!(&entries[2].1, Value::Text(t) if t == "/var/log/qft # not a comment"));
+        assert!(matches!(entries[3].1, Value::Bool(false)));
+        assert!(matches!(&entries[4].1, Value::List(l) if l == &["0.0.0.0:4277", "[::]:4277"]));
+    }
+
+    #[test]
+    #[should_panic(expected = ":2: expected key = value")]
+    fn tables_are_refused() {
+        parse("table", "port = 4277\n[helper]\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "lists have to end on the same line")]
+    fn multiline_lists_are_refused() {
+        parse("list", "bind = [\n\"0.0.0.0:4277\"]\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a string, integer, boolean or list of strings")]
+    fn bare_words_are_refused() {
+        parse("word", "log = stdout\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid key")]
+    fn dotted_keys_are_refused() {
+        parse("key", "helper.port = 4277\n");
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(string("\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(string("'a \" b'").as_deref(), Some("a \" b"));
+        assert_eq!(string("\"a\\nb\""), None);
+        assert_eq!(string("\"a\" \"b\""), None);
+        assert_eq!(string("\"a"), None);
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use crate::{bind_dual_stack, canonical_addr, os_random, portmap, sha256, unix_millis};
+
+const MAGIC: &[u8] = b"qft-direct";
+
+// [MAGIC][kind: u8][...]. Both ends prove that they know the phrase without sending it, by
+// answering the other's nonce with an HMAC keyed with the phrase. The connecting end proves it
+// first, so the listener doesn't give anybody something to try guessing the phrase against.
+// HELLO: [connector nonce: 16]
+const HELLO: u8 = 0;
+// CHALLENGE: [listener nonce: 16]
+const CHALLENGE: u8 = 1;
+// AUTH: [HMAC("connect" + connector nonce + listener nonce): 32]
+const AUTH: u8 = 2;
+// DONE: [HMAC("listen" + connector nonce + listener nonce): 32]
+const DONE: u8 = 3;
+// REJECT: no payload, the proof in AUTH was wrong
+const REJECT: u8 = 4;
+
+/// Handshakes of other sources are forgotten after this long without their AUTH.
+const HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+/// At most this many handshakes at once, so a flood of HELLOs doesn't take up memory.
+const MAX_HANDSHAKES: usize = 256;
+
+fn packet(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::from(MAGIC);
+    packet.push(kind);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn parse(buf: &[u8]) -> Option<(u8, &[u8])> {
+    if buf.len() <= MAGIC.len() || &buf[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    Some((buf[MAGIC.len()], &buf[MAGIC.len() + 1..]))
+}
+
+fn random_nonce() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    os_random(&mut bytes);
+    bytes
+}
+
+fn mac(phrase: &[u8], role: &[u8], connector: &[u8], listener: &[u8]) -> [u8; 32] {
+    let mut data = Vec::from(role);
+    data.extend_from_slice(connector);
+    data.extend_from_slice(listener);
+    sha256::hmac_sha256(phrase, &data)
+}
+
+/// A connector which said HELLO and hasn't proven itself yet.
+struct Handshake {
+    their_nonce: [u8; 16],
+    own_nonce: [u8; 16],
+    started: u64,
+}
+
+fn finish(socket: UdpSocket, partner: SocketAddr) -> UdpSocket {
+    socket.connect(partner).expect("connection failed");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    socket
+        .set_write_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    eprintln!("Connection successful.");
+    socket
+}
+
+/// Waits for the partner to connect to `port`, which needs to be reachable for it (a public
+/// address or a port forward). Partners with a different phrase are turned away. Returns None if
+/// nobody connected before the timeout.
+pub fn listen(port: &str, phrase: &[u8], timeout: Option<Duration>) -> Option<UdpSocket> {
+    let port: u16 = port.parse().expect("invalid port: must be integer");
+    let socket = bind_dual_stack(port).expect("unable to listen");
+    if std::env::var("QFT_PORT_MAPPING").is_ok() {
+        // the address only selects IPv4, nothing is sent there
+        if let Some(external) = portmap::map(&socket, (Ipv4Addr::UNSPECIFIED, 0).into()) {
+            eprintln!("Your partner can connect to {}.", external);
+        }
+    }
+    eprintln!("Waiting for the partner to connect to port {}...", port);
+    let give_up = timeout.map_or(u64::MAX, |t| unix_millis() + t.as_millis() as u64);
+    let mut handshakes: HashMap<SocketAddr, Handshake> = HashMap::new();
+    let mut buf = [0u8; 128];
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    while unix_millis() < give_up {
+        let now = unix_millis();
+        handshakes.retain(|_, h| now - h.started < HANDSHAKE_TIMEOUT_MS);
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        match parse(&buf[..len]) {
+            Some((HELLO, their_nonce)) if their_nonce.len() == 16 => {
+                // a repeated HELLO gets the same nonce, the partner might have its AUTH in flight
+                let handshake = match handshakes.get(&from) {
+                    Some(h) if h.their_nonce == their_nonce => h,
+                    _ if handshakes.len() >= MAX_HANDSHAKES => continue,
+                    _ => {
+                        let handshake = Handshake {
+                            their_nonce: their_nonce.try_into().unwrap(),
+                            own_nonce: random_nonce(),
+                            started: unix_millis(),
+                        };
+                        handshakes.entry(from).insert_entry(handshake).into_mut()
+                    }
+                };
+                let _ = socket.send_to(&packet(CHALLENGE, &handshake.own_nonce), from);
+            }
+            Some((AUTH, proof)) => {
+                let Some(handshake) = handshakes.remove(&from) else {
+                    continue;
+                };
+                let (theirs, own) = (&handshake.their_nonce, &handshake.own_nonce);
+                if !sha256::equal(proof, &mac(phrase, b"connect", theirs, own)) {
+                    eprintln!(
+                        "{} tried to connect with a different phrase.",
+                        canonical_addr(from)
+                    );
+                    let _ = socket.send_to(&packet(REJECT, &[]), from);
+                    continue;
+                }
+                let done = packet(DONE, &mac(phrase, b"listen", theirs, own));
+                for _ in 0..5 {
+                    let _ = socket.send_to(&done, from);
+                }
+                eprintln!("{} connected.", canonical_addr(from));
+                return Some(finish(socket, from));
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Connects to a partner which listens with `listen`. Returns None if it didn't answer before the
+/// timeout.
+pub fn connect(target: &str, phrase: &[u8], timeout: Option<Duration>) -> Option<UdpSocket> {
+    let target = target
+        .to_socket_addrs()
+        .expect("unable to resolve partner")
+        .next()
+        .expect("partner has no address");
+    let bind_addr: SocketAddr = if target.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).expect("unable to create socket");
+    socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    eprintln!("Connecting to {}...", target);
+    let give_up = timeout.map_or(u64::MAX, |t| unix_millis() + t.as_millis() as u64);
+    let own_nonce = random_nonce();
+    // the listener's nonce, once it sent one
+    let mut their_nonce: Option<Vec<u8>> = None;
+    let mut last_send = 0;
+    let mut buf = [0u8; 128];
+    while unix_millis() < give_up {
+        // the partner might not listen yet, so keep trying
+        if unix_millis() - last_send >= 500 {
+            last_send = unix_millis();
+            let message = match &their_nonce {
+                Some(nonce) => packet(AUTH, &mac(phrase, b"connect", &own_nonce, nonce)),
+                None => packet(HELLO, &own_nonce),
+            };
+            let _ = socket.send_to(&message, target);
+        }
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if canonical_addr(from) != canonical_addr(target) {
+            continue;
+        }
+        match parse(&buf[..len]) {
+            Some((CHALLENGE, nonce)) if nonce.len() == 16 && their_nonce.is_none() => {
+                their_nonce = Some(nonce.to_vec());
+                last_send = 0;
+            }
+            Some((DONE, proof)) => {
+                let Some(nonce) = &their_nonce else {
+                    continue;
+                };
+                if sha256::equal(proof, &mac(phrase, b"listen", &own_nonce, nonce)) {
+                    return Some(finish(socket, from));
+                }
+                // not from the partner, which only answers once it checked our proof
+                eprintln!("Ignoring a wrong answer from {}.", target);
+            }
+            Some((REJECT, _)) if their_nonce.is_some() => {
+                eprintln!("{} uses a different phrase.", target);
+                return None;
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn free_port() -> u16 {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        socket.local_addr().unwrap().port()
+    }
+
+    /// Runs listen and connect at once, with their own phrases. Also returns how long connect
+    /// took.
+    fn handshake(
+        listener: &'static [u8],
+        connector: &'static [u8],
+    ) -> (Option<UdpSocket>, Option<UdpSocket>, u64) {
+        let port = free_port();
+        let listening = thread::spawn(move || {
+            listen(&port.to_string(), listener, Some(Duration::from_secs(2)))
+        });
+        let start = unix_millis();
+        let target = format!("127.0.0.1:{}", port);
+        let connected = connect(&target, connector, Some(Duration::from_secs(10)));
+        let took = unix_millis() - start;
+        (listening.join().unwrap(), connected, took)
+    }
+
+    #[test]
+    fn accepts_the_same_phrase() {
+        let (listened, connected, _) = handshake(b"direct phrase", b"direct phrase");
+        let (listened, connected) = (listened.unwrap(), connected.unwrap());
+        assert_eq!(
+            canonical_addr(listened.peer_addr().unwrap()).port(),
+            connected.local_addr().unwrap().port()
+        );
+        connected.send(b"hello").unwrap();
+        let mut buf = [0u8; 128];
+        let len = listened.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+
+    #[test]
+    fn rejects_another_phrase() {
+        let (listened, connected, took) = handshake(b"direct phrase", b"wrong phrase");
+        assert!(listened.is_none());
+        assert!(connected.is_none());
+        // the connector was told instead of waiting for its timeout
+        assert!(took < 5000);
+    }
+
+    #[test]
+    fn macs_bind_role_and_nonces() {
+        let (a, b) = ([1u8; 16], [2u8; 16]);
+        let connect = mac(b"phrase", b"connect", &a, &b);
+        assert_ne!(connect, mac(b"phrase", b"listen", &a, &b));
+        assert_ne!(connect, mac(b"phrase", b"connect", &b, &a));
+        assert_ne!(connect, mac(b"other", b"connect", &a, &b));
+        assert_eq!(parse(&packet(AUTH, &connect)), Some((AUTH, &connect[..])));
+        assert_eq!(parse(b"qft-direc"), None);
+    }
+}
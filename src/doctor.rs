@@ -0,0 +1,290 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{helper_sockets, ice, print_args, stun};
+
+const HAIRPIN_MAGIC: &[u8] = b"qft-hairpin";
+
+/// How the NAT picks the public port for a new mapping.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Allocation {
+    /// The public port is the local port.
+    Preserving,
+    /// Each new mapping gets the previous public port plus this.
+    Sequential(i32),
+    Random,
+}
+
+/// Looks at (local port, public port) pairs of mappings made one after another.
+pub fn classify(samples: &[(u16, u16)]) -> Allocation {
+    if samples.iter().all(|(local, public)| local == public) {
+        return Allocation::Preserving;
+    }
+    let deltas: Vec<i32> = samples
+        .windows(2)
+        .map(|w| w[1].1 as i32 - w[0].1 as i32)
+        .collect();
+    match deltas.first() {
+        Some(d) if *d != 0 && d.abs() <= 16 && deltas.iter().all(|x| x == d) => {
+            Allocation::Sequential(*d)
+        }
+        _ => Allocation::Random,
+    }
+}
+
+/// Makes `count` new mappings by asking the helper from new sockets, returns
+/// (local port, public port) for each.
+pub fn probe_allocation(helper: SocketAddr, bind: SocketAddr, count: usize) -> Vec<(u16, u16)> {
+    let mut samples = vec![];
+    for _ in 0..count {
+        let Ok(socket) = UdpSocket::bind(bind) else {
+            continue;
+        };
+        if let Ok(mapped) = stun::binding(&socket, helper, Duration::from_secs(2)) {
+            samples.push((socket.local_addr().unwrap().port(), mapped.port()));
+        }
+    }
+    samples
+}
+
+/// Finds out how the NAT between us and the helper behaves, and explains what that means for
+/// holepunching.
+pub fn doctor(args: &[String]) {
+    let helper = args.get(2).unwrap_or_else(|| {
+        print_args(args);
+        panic!("unreachable")
+    });
+    let second_server = args
+        .get(3)
+        .map(|s| stun::resolve(s).expect("unable to resolve second STUN server"));
+    let sockets = helper_sockets(helper);
+    if sockets.is_empty() {
+        println!(
+            "{} can't be reached at all. Check the address and your connection.",
+            helper
+        );
+        return;
+    }
+    for (socket, helper_addr) in sockets {
+        println!();
+        println!(
+            "== {} (helper at {}) ==",
+            if helper_addr.is_ipv6() {
+                "IPv6"
+            } else {
+                "IPv4"
+            },
+            helper_addr
+        );
+        examine(&socket, helper_addr, second_server);
+    }
+}
+
+fn examine(socket: &UdpSocket, helper: SocketAddr, second_server: Option<SocketAddr>) {
+    let first = match stun::request(socket, helper, false, Duration::from_secs(5)) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("The helper doesn't answer ({}).", e);
+            println!(
+                "Either it can't be reached this way, or it is too old to answer diagnostics. If \
+                 your transfers hang at \"Connecting...\", this is likely why."
+            );
+            return;
+        }
+    };
+    let local = socket.local_addr().unwrap();
+    println!(
+        "Your public address is {} (local port {}).",
+        first.mapped,
+        local.port()
+    );
+    let behind_nat = !first.mapped.ip().is_loopback()
+        && !ice::host_candidates(socket, helper)
+            .iter()
+            .any(|c| c.addr.ip() == first.mapped.ip());
+    if !behind_nat {
+        println!("You are not behind a NAT, partners can reach you directly (unless a firewall blocks them).");
+    }
+
+    // Mapping: does a different destination get a different public address?
+    let other = match (first.other_port, second_server) {
+        (Some(port), _) => Some(SocketAddr::new(helper.ip(), port)),
+        (None, Some(server)) if server.is_ipv6() == helper.is_ipv6() => Some(server),
+        _ => None,
+    };
+    let mapping_independent = match other {
+        Some(other) => match stun::binding(socket, other, Duration::from_secs(3)) {
+            Ok(mapped) if mapped == first.mapped => {
+                println!("Mapping: endpoint-independent (the same public address for every destination).");
+                Some(true)
+            }
+            Ok(mapped) => {
+                println!(
+                    "Mapping: {}-dependent, the NAT is symmetric ({} was seen as {}).",
+                    if other.ip() == helper.ip() {
+                        "port"
+                    } else {
+                        "address"
+                    },
+                    other,
+                    mapped
+                );
+                Some(false)
+            }
+            Err(e) => {
+                println!("Mapping: unknown, {} didn't answer ({}).", other, e);
+                None
+            }
+        },
+        None => {
+            println!(
+                "Mapping: unknown. Run the helper with a diagnostics port, or give a second STUN \
+                 server: qft doctor <helper> <stun-server>"
+            );
+            None
+        }
+    };
+
+    // Filtering: does the NAT let in packets from a port we never sent anything to?
+    let filtering_open = match first.other_port {
+        Some(_) => {
+            let fresh = UdpSocket::bind(SocketAddr::new(local.ip(), 0)).unwrap();
+            match stun::request(&fresh, helper, true, Duration::from_secs(3)) {
+                Ok(r) if r.from.port() != helper.port() => {
+                    println!("Filtering: endpoint-independent or address-dependent (other ports of a known address get through).");
+                    Some(true)
+                }
+                _ => {
+                    println!("Filtering: address- and port-dependent (only exactly the addresses you sent to get through).");
+                    Some(false)
+                }
+            }
+        }
+        None => {
+            println!("Filtering: unknown, the helper has no diagnostics port.");
+            None
+        }
+    };
+
+    // Port allocation: how predictable are new public ports?
+    let samples = probe_allocation(helper, SocketAddr::new(local.ip(), 0), 5);
+    match classify(&samples) {
+        _ if samples.len() < 2 => println!("Port allocation: unknown."),
+        Allocation::Preserving => println!("Port allocation: port-preserving."),
+        Allocation::Sequential(d) => {
+            println!(
+                "Port allocation: sequential, each new mapping is {:+} from the last.",
+                d
+            )
+        }
+        Allocation::Random => println!(
+            "Port allocation: random ({}).",
+            samples
+                .iter()
+                .map(|(l, p)| format!("{}->{}", l, p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+
+    // Hairpinning: can we reach our own public address from inside?
+    let hairpinning = hairpinning(helper, SocketAddr::new(local.ip(), 0));
+    match hairpinning {
+        Some(true) => println!("Hairpinning: supported."),
+        Some(false) => println!("Hairpinning: not supported."),
+        None => println!("Hairpinning: unknown."),
+    }
+
+    println!();
+    match (behind_nat, mapping_independent, filtering_open) {
+        (false, _, _) => println!(
+            "Verdict: direct connections should work with any partner that can reach the internet."
+        ),
+        (true, Some(true), _) => println!(
+            "Verdict: holepunching should work with almost any partner. If your partner's NAT is \
+             symmetric, it can still work because your mapping stays the same."
+        ),
+        (true, Some(false), Some(false)) => println!(
+            "Verdict: your NAT is symmetric and strict. Holepunching will most likely only work if \
+             your partner is not behind a NAT, or has a port forward."
+        ),
+        (true, Some(false), _) => println!(
+            "Verdict: your NAT is symmetric. Holepunching only works if your partner's NAT is not \
+             symmetric too, and even then not always."
+        ),
+        (true, None, _) => println!(
+            "Verdict: unknown, the mapping behavior couldn't be tested. Most home routers are \
+             fine, mobile networks and company networks are often not."
+        ),
+    }
+    if mapping_independent == Some(false) {
+        println!(
+            "Set QFT_PORT_PREDICTION on this end, so qft measures your NAT and your partner tries \
+             to guess its ports."
+        );
+    }
+    if hairpinning == Some(false) && behind_nat {
+        println!(
+            "Partners behind the same router connect over their local addresses instead, qft does \
+             that automatically."
+        );
+    }
+}
+
+/// Sends a packet from one socket to the other socket's public address.
+fn hairpinning(helper: SocketAddr, bind: SocketAddr) -> Option<bool> {
+    let a = UdpSocket::bind(bind).ok()?;
+    let b = UdpSocket::bind(bind).ok()?;
+    let a_public = stun::binding(&a, helper, Duration::from_secs(2)).ok()?;
+    let b_public = stun::binding(&b, helper, Duration::from_secs(2)).ok()?;
+    // open b's filter for a first, then try both directions
+    let _ = b.send_to(HAIRPIN_MAGIC, a_public);
+    let _ = a.send_to(HAIRPIN_MAGIC, b_public);
+    let _ = b.send_to(HAIRPIN_MAGIC, a_public);
+    let mut buf = [0u8; 32];
+    for socket in [&a, &b] {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(1000)))
+            .unwrap();
+        while let Ok(len) = socket.recv(&mut buf) {
+            if &buf[..len] == HAIRPIN_MAGIC {
+                return Some(true);
+            }
+        }
+    }
+    Some(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_allocation() {
+        assert_eq!(
+            classify(&[(5000, 5000), (5001, 5001)]),
+            Allocation::Preserving
+        );
+        assert_eq!(
+            classify(&[(5000, 6000), (5001, 6001), (5002, 6002)]),
+            Allocation::Sequential(1)
+        );
+        assert_eq!(
+            classify(&[(5000, 6010), (5001, 6006), (5002, 6002)]),
+            Allocation::Sequential(-4)
+        );
+        // too far apart to guess, or not the same step
+        assert_eq!(
+            classify(&[(5000, 6000), (5001, 6100), (5002, 6200)]),
+            Allocation::Random
+        );
+        assert_eq!(
+            classify(&[(5000, 6000), (5001, 6001), (5002, 6003)]),
+            Allocation::Random
+        );
+        assert_eq!(classify(&[(5000, 6000), (5001, 6000)]), Allocation::Random);
+        assert_eq!(classify(&[(5000, 6000)]), Allocation::Random);
+    }
+}
@@ -0,0 +1,382 @@
+// Protects the helper and others from abuse: limits how often each IP may ask for something, and
+// keeps the helper from being used to flood someone else, by never answering with more than it
+// got and, optionally, checking that peers really own their address before pairing them. Private
+// helpers also only pair peers which know one of their tokens.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{os_random, sha256, unix_millis};
+
+/// The helper's challenge: [COOKIE_MAGIC][cookie: 16]. Peers send it back the same way before
+/// registering.
+pub const COOKIE_MAGIC: &[u8] = b"qft-cookie";
+const COOKIE_LEN: usize = 16;
+/// Cookies are valid for the minute they were made in and the next one.
+const COOKIE_PERIOD_MS: u64 = 60_000;
+
+const KEY_ID_LEN: usize = 8;
+/// Credentials are accepted this long before and after the time they were made, so the clocks of
+/// peers and helper have to be about right. Each one is accepted only once in that time.
+const CREDENTIAL_WINDOW_MS: u64 = 300_000;
+/// At most this many used credentials are remembered. When that many are still in their window,
+/// new ones are refused until some expire.
+const MAX_USED_CREDENTIALS: usize = 100_000;
+
+struct Bucket {
+    // in thousandths of a packet, refilled continuously
+    tokens: u64,
+    last_refill: u64,
+}
+
+/// A token bucket per source IP. Peers behind the same NAT share one.
+pub struct RateLimit {
+    // packets per second, 0 for no limit
+    rate: u64,
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: u64,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> RateLimit {
+        RateLimit {
+            rate,
+            buckets: HashMap::new(),
+            last_sweep: 0,
+        }
+    }
+
+    /// Bursts of up to two seconds worth of packets are fine.
+    fn capacity(&self) -> u64 {
+        self.rate * 2 * 1000
+    }
+
+    /// Returns whether a packet from `ip` should be handled.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        self.sweep();
+        let now = unix_millis();
+        let capacity = self.capacity();
+        let rate = self.rate;
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        bucket.tokens = (bucket.tokens + rate * (now - bucket.last_refill)).min(capacity);
+        bucket.last_refill = now;
+        if bucket.tokens < 1000 {
+            return false;
+        }
+        bucket.tokens -= 1000;
+        true
+    }
+
+    // Full buckets are the same as none, so only IPs which sent something lately take up memory.
+    fn sweep(&mut self) {
+        let now = unix_millis();
+        if now - self.last_sweep < 1000 {
+            return;
+        }
+        self.last_sweep = now;
+        let (rate, capacity) = (self.rate, self.capacity());
+        self.buckets
+            .retain(|_, b| b.tokens + rate * (now - b.last_refill) < capacity);
+    }
+}
+
+/// Cookies which only someone who receives packets at an address can send back, so registrations
+/// with a forged source address are turned away. They aren't stored, only the addresses which sent
+/// the right one are, for a while.
+pub struct Cookies {
+    secret: [u8; 32],
+    verified: HashMap<SocketAddr, u64>,
+    last_sweep: u64,
+}
+
+impl Cookies {
+    pub fn new() -> Cookies {
+        let mut secret = [0u8; 32];
+        os_random(&mut secret);
+        Cookies {
+            secret,
+            verified: HashMap::new(),
+            last_sweep: 0,
+        }
+    }
+
+    fn cookie(&self, addr: SocketAddr, period: u64) -> Vec<u8> {
+        let data = format!("{} {}", addr, period);
+        sha256::hmac_sha256(&self.secret, data.as_bytes())[..COOKIE_LEN].to_vec()
+    }
+
+    /// The cookie for `addr`, protocol v2 sends it in a field.
+    pub fn current(&self, addr: SocketAddr) -> Vec<u8> {
+        self.cookie(addr, unix_millis() / COOKIE_PERIOD_MS)
+    }
+
+    /// Whether `cookie` is the one for `addr`, from this period or the last one.
+    pub fn valid(&self, cookie: &[u8], addr: SocketAddr) -> bool {
+        let period = unix_millis() / COOKIE_PERIOD_MS;
+        sha256::equal(cookie, &self.cookie(addr, period))
+            || sha256::equal(cookie, &self.cookie(addr, period - 1))
+    }
+
+    /// The challenge for `addr`.
+    pub fn challenge(&self, addr: SocketAddr) -> Vec<u8> {
+        let mut packet = Vec::from(COOKIE_MAGIC);
+        packet.extend(self.current(addr));
+        packet
+    }
+
+    /// Returns whether `packet` is an answer to a challenge, and remembers `addr` if it is the
+    /// right one.
+    pub fn check(&mut self, packet: &[u8], addr: SocketAddr) -> bool {
+        if packet.len() != COOKIE_MAGIC.len() + COOKIE_LEN || !packet.starts_with(COOKIE_MAGIC) {
+            return false;
+        }
+        if self.valid(&packet[COOKIE_MAGIC.len()..], addr) {
+            self.verified.insert(addr, unix_millis());
+        }
+        true
+    }
+
+    /// Whether `addr` sent the right cookie lately.
+    pub fn verified(&mut self, addr: SocketAddr) -> bool {
+        let now = unix_millis();
+        if now - self.last_sweep >= 1000 {
+            self.last_sweep = now;
+            self.verified
+                .retain(|_, time| now - *time < 2 * COOKIE_PERIOD_MS);
+        }
+        self.verified.contains_key(&addr)
+    }
+}
+
+/// Sends `reply` to `addr` unless it is bigger than the `budget` bytes which came from there.
+pub fn reply(socket: &UdpSocket, reply: &[u8], budget: usize, addr: SocketAddr) {
+    if reply.len() <= budget {
+        let _ = socket.send_to(reply, addr);
+    }
+}
+
+/// Proves that a peer knows a token, without sending it: [key ID: 8][time: u64 BE][MAC: 32], where
+/// the key ID is the start of the token's SHA-256 and the MAC is HMAC-SHA256 with the token over
+/// `purpose`, key ID, time and `data`. Registrations use the purpose `register` and the phrase as
+/// data, so a credential can't be used for another phrase. Helpers accept each credential once, so
+/// every request needs a new one.
+pub fn credential(token: &[u8], purpose: &[u8], data: &[u8]) -> Vec<u8> {
+    // the time tells credentials apart, so two of them never have the same
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = unix_millis();
+    let last = LAST
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap();
+    credential_at(token, purpose, data, now.max(last + 1))
+}
+
+fn credential_at(token: &[u8], purpose: &[u8], data: &[u8], time: u64) -> Vec<u8> {
+    let id = key_id(token);
+    let time = time.to_be_bytes();
+    let mut credential = id.to_vec();
+    credential.extend(time);
+    credential.extend(mac(token, purpose, &id, &time, data));
+    credential
+}
+
+fn key_id(token: &[u8]) -> [u8; KEY_ID_LEN] {
+    sha256::sha256(token)[..KEY_ID_LEN].try_into().unwrap()
+}
+
+fn mac(token: &[u8], purpose: &[u8], id: &[u8], time: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut message = purpose.to_vec();
+    message.extend(id);
+    message.extend(time);
+    message.extend(data);
+    sha256::hmac_sha256(token, &message)
+}
+
+/// The tokens of a private helper, by key ID, and the credentials which were used lately.
+pub struct Tokens {
+    tokens: HashMap<[u8; KEY_ID_LEN], Vec<u8>>,
+    // MACs of used credentials, until they are too old anyway
+    used: Mutex<HashMap<[u8; 32], u64>>,
+}
+
+impl Tokens {
+    pub fn new(tokens: &[String]) -> Tokens {
+        Tokens {
+            tokens: tokens
+                .iter()
+                .map(|token| (key_id(token.as_bytes()), token.as_bytes().to_vec()))
+                .collect(),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `credential` was made for `purpose` and `data` with one of the tokens, lately, and
+    /// wasn't used before. Someone who sees a credential on its way can still use it first, but
+    /// only for the same purpose and data within CREDENTIAL_WINDOW_MS.
+    pub fn valid(&self, credential: &[u8], purpose: &[u8], data: &[u8]) -> bool {
+        if credential.len() != KEY_ID_LEN + 8 + 32 {
+            return false;
+        }
+        let (id, rest) = credential.split_at(KEY_ID_LEN);
+        let (time, proof) = rest.split_at(8);
+        let Some(token) = self.tokens.get(id) else {
+            return false;
+        };
+        let now = unix_millis();
+        let made = u64::from_be_bytes(time.try_into().unwrap());
+        if now.abs_diff(made) > CREDENTIAL_WINDOW_MS
+            || !sha256::equal(proof, &mac(token, purpose, id, time, data))
+        {
+            return false;
+        }
+        let mut used = self.used.lock().unwrap();
+        if used.len() >= MAX_USED_CREDENTIALS {
+            used.retain(|_, expires| *expires >= now);
+        }
+        if used.len() >= MAX_USED_CREDENTIALS {
+            return false;
+        }
+        used.insert(proof.try_into().unwrap(), made + CREDENTIAL_WINDOW_MS)
+            .is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_allows_bursts() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let mut limit = RateLimit::new(2);
+        for _ in 0..4 {
+            assert!(limit.allow(ip));
+        }
+        assert!(!limit.allow(ip));
+        // others have their own bucket
+        assert!(limit.allow(IpAddr::from([192, 0, 2, 2])));
+    }
+
+    #[test]
+    fn rate_limit_zero_is_unlimited() {
+        let mut limit = RateLimit::new(0);
+        for _ in 0..1000 {
+            assert!(limit.allow(IpAddr::from([192, 0, 2, 1])));
+        }
+    }
+
+    #[test]
+    fn cookies_verify_their_address() {
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let other: SocketAddr = "192.0.2.1:4001".parse().unwrap();
+        let mut cookies = Cookies::new();
+        assert!(cookies.valid(&cookies.current(addr), addr));
+        assert!(!cookies.valid(&cookies.current(addr), other));
+        assert!(!cookies.verified(addr));
+        assert!(cookies.check(&cookies.challenge(addr), addr));
+        assert!(cookies.verified(addr));
+        // a wrong answer is still an answer, but doesn't verify
+        assert!(cookies.check(&cookies.challenge(addr), other));
+        assert!(!cookies.verified(other));
+        assert!(!cookies.check(b"qft-cookie", other));
+    }
+
+    #[test]
+    fn cookies_differ_between_helpers() {
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        assert_ne!(Cookies::new().current(addr), Cookies::new().current(addr));
+    }
+
+    #[test]
+    fn reply_stays_within_budget() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let addr = receiver.local_addr().unwrap();
+        reply(&socket, &[1; 10], 9, addr);
+        reply(&socket, &[2; 10], 10, addr);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut buf = [0u8; 16];
+        assert_eq!(receiver.recv_from(&mut buf).unwrap().0, 10);
+        assert_eq!(buf[0], 2);
+        assert!(receiver.recv_from(&mut buf).is_err());
+    }
+
+    fn tokens() -> Tokens {
+        Tokens::new(&["first".to_owned(), "second".to_owned()])
+    }
+
+    #[test]
+    fn round_trip() {
+        let tokens = tokens();
+        for token in ["first", "second"] {
+            let credential = credential(token.as_bytes(), b"register", b"phrase");
+            assert!(tokens.valid(&credential, b"register", b"phrase"));
+        }
+    }
+
+    #[test]
+    fn used_only_once() {
+        let tokens = tokens();
+        let credential = credential(b"first", b"register", b"phrase");
+        assert!(tokens.valid(&credential, b"register", b"phrase"));
+        assert!(!tokens.valid(&credential, b"register", b"phrase"));
+        // the next one is different
+        assert!(tokens.valid(
+            &super::credential(b"first", b"register", b"phrase"),
+            b"register",
+            b"phrase"
+        ));
+    }
+
+    #[test]
+    fn wrong_token_purpose_or_data() {
+        let tokens = tokens();
+        assert!(!tokens.valid(
+            &credential(b"third", b"register", b"phrase"),
+            b"register",
+            b"phrase"
+        ));
+        assert!(!tokens.valid(&credential(b"first", b"status", b""), b"nameplate", b""));
+        assert!(!tokens.valid(
+            &credential(b"first", b"register", b"phrase"),
+            b"register",
+            b"other"
+        ));
+        let mut credential = credential(b"first", b"status", b"");
+        *credential.last_mut().unwrap() ^= 1;
+        assert!(!tokens.valid(&credential, b"status", b""));
+        assert!(!tokens.valid(&credential[1..], b"status", b""));
+    }
+
+    #[test]
+    fn expiry() {
+        let tokens = tokens();
+        let now = unix_millis();
+        let old = credential_at(b"first", b"status", b"", now - CREDENTIAL_WINDOW_MS - 1000);
+        assert!(!tokens.valid(&old, b"status", b""));
+        let future = credential_at(b"first", b"status", b"", now + CREDENTIAL_WINDOW_MS + 1000);
+        assert!(!tokens.valid(&future, b"status", b""));
+        let recent = credential_at(b"first", b"status", b"", now - CREDENTIAL_WINDOW_MS / 2);
+        assert!(tokens.valid(&recent, b"status", b""));
+    }
+
+    #[test]
+    fn credentials_differ() {
+        let first = credential(b"first", b"status", b"");
+        assert_ne!(first, credential(b"first", b"status", b""));
+    }
+}
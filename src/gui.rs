@@ -236,7 +236,7 @@ pub fn gui() -> Result<(), iui::UIError> {
                     let lpb = RefMut::new(&mut last_percentage);
                     let uib1 = uib.clone();
                     let barb1 = barb.clone();
-                    crate::receiver(&args, move |f| {
+                    let result = crate::receiver(&args, move |f| {
                         let lpb1 = lpb.clone();
                         let uib = uib1.clone();
                         let barb = barb1.clone();
@@ -251,12 +251,21 @@ pub fn gui() -> Result<(), iui::UIError> {
                             }
                         })
                     });
+                    crate::portmap::unmap_all();
+                    // a failed connection shows as an empty bar, the reason goes to stderr
+                    let done = match result {
+                        Ok(()) => 100,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            0
+                        }
+                    };
                     let uib1 = uib.clone();
                     let barb1 = barb.clone();
                     uib.get().queue_main(move || {
                         barb1
                             .get()
-                            .set_value(uib1.get(), ProgressBarValue::Determinate(100));
+                            .set_value(uib1.get(), ProgressBarValue::Determinate(done));
                         bb.get().enable(uib1.get());
                     });
                 });
@@ -272,7 +281,7 @@ pub fn gui() -> Result<(), iui::UIError> {
                     let lpb = RefMut::new(&mut last_percentage);
                     let uib1 = uib.clone();
                     let barb1 = barb.clone();
-                    crate::sender(&args, move |f| {
+                    let result = crate::sender(&args, move |f| {
                         let lpb1 = lpb.clone();
                         let uib = uib1.clone();
                         let barb = barb1.clone();
@@ -287,12 +296,21 @@ pub fn gui() -> Result<(), iui::UIError> {
                             }
                         })
                     });
+                    crate::portmap::unmap_all();
+                    // a failed connection shows as an empty bar, the reason goes to stderr
+                    let done = match result {
+                        Ok(()) => 100,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            0
+                        }
+                    };
                     let uib1 = uib.clone();
                     let barb1 = barb.clone();
                     uib.get().queue_main(move || {
                         barb1
                             .get()
-                            .set_value(uib1.get(), ProgressBarValue::Determinate(100));
+                            .set_value(uib1.get(), ProgressBarValue::Determinate(done));
                         bb.get().enable(uib1.get());
                     });
                 });
@@ -0,0 +1,904 @@
+// The helper: pairs peers which registered the same phrase, and answers the STUN, time and relay
+// requests which help them connect. Several threads receive from the same socket, and the state
+// they share is split into shards, so it keeps up with many thousands of pairings per second.
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    process,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use time::{Date, PrimitiveDateTime, Time};
+
+use crate::{
+    bind_dual_stack, canonical_addr,
+    config::{self, Value},
+    guard::{self, Cookies, RateLimit, Tokens},
+    ice::CANDIDATES_MAGIC,
+    metrics::{self, Metrics},
+    nonce,
+    pairing::{self, Candidates, Nameplates, Outcome, Pairings, Registration},
+    print_args,
+    protocol::{self, Kind, Message, Protocol, Role},
+    punch,
+    relay::{self, Relay},
+    shard::Shards,
+    stun, take_flag, take_option, unix_millis, HolepunchError, Wrap,
+};
+
+/// Pairings which weren't logged yet. If the log falls behind, more are left out of it.
+const LOG_QUEUE: usize = 65536;
+
+/// What the threads share.
+struct State {
+    pairings: Shards<Pairings>,
+    candidates: Shards<Candidates>,
+    limits: Shards<RateLimit>,
+    // peers have to prove that they own their address before they are paired, which older
+    // versions can't do
+    cookies: Option<Shards<Cookies>>,
+    // private helpers ignore everyone who doesn't know a token
+    tokens: Option<Tokens>,
+    nameplates: Mutex<Nameplates>,
+    relay: Mutex<Relay>,
+    relaying: bool,
+    stun: bool,
+    diagnostics: Option<UdpSocket>,
+    log: SyncSender<(SocketAddr, SocketAddr)>,
+    metrics: Metrics,
+    /// What the helper listens on. Each has its own threads.
+    sockets: Vec<UdpSocket>,
+}
+
+pub fn helper(args: &[String]) {
+    let mut args = args.to_vec();
+    // flags win over the config file, they are only added if they aren't there yet
+    let config_ports = take_option(&mut args, "--config")
+        .map(|path| apply_config(&mut args, &path))
+        .unwrap_or_default();
+    let relay_limits = relay_limits(&mut args).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        process::exit(1);
+    });
+    if let Some(limits) = &relay_limits {
+        println!(
+            "Relaying for peers who allow it, at most {} KiB/s and {} MiB per session.",
+            limits.rate / 1024,
+            limits.quota / 1024 / 1024
+        );
+    }
+    let relaying = relay_limits.is_some();
+    let waiting_ttl = take_option(&mut args, "--waiting-ttl")
+        .map(|s| {
+            s.parse::<u64>()
+                .expect("invalid waiting TTL: must be integer")
+        })
+        .unwrap_or(600);
+    let max_waiting = take_option(&mut args, "--max-waiting")
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("invalid waiting limit: must be integer")
+        })
+        .unwrap_or(100_000);
+    let max_waiting_per_ip = take_option(&mut args, "--max-waiting-per-ip")
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("invalid waiting limit: must be integer")
+        })
+        .unwrap_or(32);
+    let rate_limit = take_option(&mut args, "--rate-limit")
+        .map(|s| {
+            s.parse::<u64>()
+                .expect("invalid rate limit: must be integer")
+        })
+        .unwrap_or(50);
+    let cookie = take_flag(&mut args, "--cookie");
+    let mut tokens = vec![];
+    while let Some(token) = take_option(&mut args, "--token") {
+        tokens.push(token);
+    }
+    if !tokens.is_empty() {
+        println!(
+            "Private helper: only pairing peers with one of the {} tokens.",
+            tokens.len()
+        );
+    }
+    let threads = take_option(&mut args, "--threads")
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("invalid thread count: must be integer")
+        })
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
+    let metrics_port = take_option(&mut args, "--metrics").map(|s| {
+        s.parse::<u16>()
+            .expect("invalid metrics port: must be integer")
+    });
+    let stun = !take_flag(&mut args, "--no-stun");
+    let log = take_option(&mut args, "--log").unwrap_or("qft_helper_log.txt".to_owned());
+    let log_interval = take_option(&mut args, "--log-interval")
+        .map(|s| {
+            s.parse::<u64>()
+                .expect("invalid log interval: must be integer")
+        })
+        .unwrap_or(10);
+    let mut binds = vec![];
+    while let Some(bind) = take_option(&mut args, "--bind") {
+        binds.push(bind);
+    }
+    let [config_port, config_diagnostics_port] = config_ports;
+    let port = args
+        .get(2)
+        .or(config_port.as_ref())
+        .map(|p| p.parse::<u16>().expect("invalid port: must be integer"));
+    let sockets: Vec<UdpSocket> = if binds.is_empty() {
+        let Some(port) = port else {
+            print_args(&args);
+            panic!("unreachable")
+        };
+        vec![bind_dual_stack(port).expect("unable to create socket")]
+    } else {
+        binds
+            .iter()
+            .map(|bind| {
+                // the port can be left out if it is given as argument
+                let addr = SocketAddr::from_str(bind)
+                    .ok()
+                    .or_else(|| {
+                        let ip = IpAddr::from_str(bind.trim_matches(['[', ']'])).ok()?;
+                        Some(SocketAddr::new(ip, port?))
+                    })
+                    .expect(
+                        "invalid bind address: must be <ip>:<port>, or <ip> and a port argument",
+                    );
+                UdpSocket::bind(addr).expect("unable to create socket")
+            })
+            .collect()
+    };
+    // The diagnostics port only answers STUN, so `qft doctor` can see how the NAT treats a second
+    // destination port.
+    let diagnostics = args
+        .get(3)
+        .or(config_diagnostics_port.as_ref())
+        .filter(|_| stun)
+        .map(|p| {
+            let port = p
+                .parse::<u16>()
+                .expect("invalid diagnostics port: must be integer");
+            bind_dual_stack(port).expect("unable to create diagnostics socket")
+        });
+    if let Some(diagnostics) = &diagnostics {
+        let diagnostics = diagnostics.try_clone().unwrap();
+        // the "other port" the answers on the diagnostics port mention
+        let listener = sockets[0].try_clone().unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut buf = [0u8; 576];
+            let mut limit = RateLimit::new(rate_limit);
+            loop {
+                let Ok((l, addr)) = diagnostics.recv_from(&mut buf) else {
+                    continue;
+                };
+                if !limit.allow(addr.ip()) {
+                    continue;
+                }
+                if let Some((response, change_port)) =
+                    stun::answer(&buf[..l], canonical_addr(addr), Some(port))
+                {
+                    let socket = if change_port { &listener } else { &diagnostics };
+                    guard::reply(socket, &response, l, addr);
+                }
+            }
+        });
+    }
+
+    // a few shards per thread, so two threads rarely want the same one
+    let shards = threads * sockets.len() * 4;
+    let per_ip = Arc::new(Shards::new(shards, HashMap::new));
+    let (log_queue, logged) = mpsc::sync_channel(LOG_QUEUE);
+    let addrs: Vec<String> = sockets
+        .iter()
+        .map(|socket| socket.local_addr().unwrap().to_string())
+        .collect();
+    let state = Arc::new(State {
+        pairings: Shards::new(shards, || {
+            Pairings::new(
+                Duration::from_secs(waiting_ttl),
+                max_waiting.div_ceil(shards),
+                max_waiting_per_ip,
+                per_ip.clone(),
+            )
+        }),
+        candidates: Shards::new(shards, || Candidates::new(max_waiting.div_ceil(shards))),
+        limits: Shards::new(shards, || RateLimit::new(rate_limit)),
+        cookies: cookie.then(|| Shards::new(shards, Cookies::new)),
+        tokens: (!tokens.is_empty()).then(|| Tokens::new(&tokens)),
+        nameplates: Mutex::new(Nameplates::new(
+            Duration::from_secs(waiting_ttl),
+            max_waiting,
+            max_waiting_per_ip,
+        )),
+        relay: Mutex::new(Relay::new(relay_limits)),
+        relaying,
+        stun,
+        diagnostics,
+        log: log_queue,
+        metrics: Metrics::new(),
+        sockets,
+    });
+    thread::spawn(move || write_log(logged, &log, log_interval));
+    if let Some(metrics_port) = metrics_port {
+        let state = state.clone();
+        metrics::serve(metrics_port, move || render(&state));
+        println!(
+            "Serving metrics on http://127.0.0.1:{}/metrics.",
+            metrics_port
+        );
+    }
+    // waiting peers are forgotten even while nothing comes in
+    {
+        let state = state.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            state
+                .pairings
+                .each(|pairings| pairings.sweep(&state.sockets));
+            state.candidates.each(|candidates| candidates.sweep());
+            state.nameplates.lock().unwrap().sweep();
+        });
+    }
+    println!(
+        "Helper listening on {} with {} threads each.",
+        addrs.join(", "),
+        threads
+    );
+    for via in 0..state.sockets.len() {
+        for _ in 0..threads {
+            let state = state.clone();
+            thread::spawn(move || work(via, state));
+        }
+    }
+    loop {
+        thread::park();
+    }
+}
+
+/// Takes --relay and the limits which go with it from the arguments. The limits are taken even
+/// without --relay, so they aren't mistaken for the port, but they are refused then.
+fn relay_limits(args: &mut Vec<String>) -> Result<Option<relay::RelayLimits>, String> {
+    let relay = take_flag(args, "--relay");
+    let rate = take_option(args, "--relay-rate");
+    let quota = take_option(args, "--relay-quota");
+    if !relay {
+        if rate.is_some() || quota.is_some() {
+            return Err(
+                "--relay-rate and --relay-quota only apply to a relaying helper, add \
+                        --relay (relay = true in the config file)"
+                    .to_owned(),
+            );
+        }
+        return Ok(None);
+    }
+    let parse = |value: Option<String>, what: &str| match value {
+        Some(value) => value
+            .parse::<u64>()
+            .map_err(|_| format!("invalid relay {}: must be integer", what)),
+        None => Ok(1024),
+    };
+    Ok(Some(relay::RelayLimits {
+        rate: parse(rate, "rate")? * 1024,
+        quota: parse(quota, "quota")? * 1024 * 1024,
+    }))
+}
+
+/// Adds the settings from the config file at `path` to the arguments as flags, unless the
+/// arguments have them already. Returns the port and diagnostics port, which are positional.
+fn apply_config(args: &mut Vec<String>, path: &str) -> [Option<String>; 2] {
+    let mut ports = [None, None];
+    let given = args.clone();
+    let has = |flag: &str| {
+        given
+            .iter()
+            .any(|arg| arg == flag || arg.starts_with(&format!("{}=", flag)))
+    };
+    for (key, value) in config::read(path) {
+        let flag = match key.as_str() {
+            // reads better for a list
+            "tokens" => "--token".to_owned(),
+            key => format!("--{}", key),
+        };
+
+        match (key.as_str(), value) {
+            ("port", Value::Integer(n)) => ports[0] = Some(n.to_string()),
+            ("diagnostics-port", Value::Integer(n)) => ports[1] = Some(n.to_string()),
+            ("relay" | "cookie", Value::Bool(on)) => {
+                if on {
+                    args.push(flag);
+                }
+            }
+            ("stun", Value::Bool(on)) => {
+                if !on {
+                    args.push("--no-stun".to_owned());
+                }
+            }
+            // flags which can be given several times
+            ("bind" | "token" | "tokens", Value::Text(value)) => {
+                if !has(&flag) {
+                    args.extend([flag, value]);
+                }
+            }
+            ("bind" | "token" | "tokens", Value::List(values)) => {
+                if !has(&flag) {
+                    for value in values {
+                        args.extend([flag.clone(), value]);
+                    }
+                }
+            }
+            (
+                "log" | "log-interval" | "waiting-ttl" | "max-waiting" | "max-waiting-per-ip"
+                | "rate-limit" | "threads" | "metrics" | "relay-rate" | "relay-quota",
+                value,
+            ) => {
+                let value = match value {
+                    Value::Text(text) => text,
+                    Value::Integer(n) => n.to_string(),
+                    _ => panic!("{}: {} needs a string or integer", path, key),
+                };
+                if !has(&flag) {
+                    args.extend([flag, value]);
+                }
+            }
+            _ => panic!("{}: unknown key or wrong type of value: {}", path, key),
+        }
+    }
+    ports
+}
+
+fn work(via: usize, state: Arc<State>) {
+    let listener = &state.sockets[via];
+    let diagnostics_port = state
+        .diagnostics
+        .as_ref()
+        .map(|d| d.local_addr().unwrap().port());
+    let mut buf = [0u8; 2048];
+    loop {
+        let Ok((l, addr)) = listener.recv_from(&mut buf) else {
+            continue;
+        };
+        let packet = &buf[..l];
+        let relayed = state.relaying && state.relay.lock().unwrap().relays(addr);
+        if !relayed && !state.limits.get(&addr.ip()).allow(addr.ip()) {
+            Metrics::count(&state.metrics.rate_limited);
+            continue;
+        }
+        // without relaying, only requests need an answer (a refusal)
+        if (state.relaying || relay::is_request(packet))
+            && state.relay.lock().unwrap().handle(listener, packet, addr)
+        {
+            continue;
+        }
+        if let Some(response) = punch::answer_time(packet) {
+            let _ = listener.send_to(&response, addr);
+            continue;
+        }
+        let stun = state
+            .stun
+            .then(|| stun::answer(packet, canonical_addr(addr), diagnostics_port));
+        if let Some((response, change_port)) = stun.flatten() {
+            let socket = match &state.diagnostics {
+                Some(diagnostics) if change_port => diagnostics,
+                _ => listener,
+            };
+            guard::reply(socket, &response, packet.len(), addr);
+            continue;
+        }
+        if let Some(registration) = registration(via, &state, packet, addr) {
+            register(listener, &state, registration);
+        }
+    }
+}
+
+/// Reads a registration in either protocol. Returns None if the packet isn't one, or if it was
+/// answered already.
+fn registration(
+    via: usize,
+    state: &State,
+    packet: &[u8],
+    addr: SocketAddr,
+) -> Option<Registration> {
+    let listener = &state.sockets[via];
+    let l = packet.len();
+    if let Some(message) = Message::decode(packet) {
+        let (purpose, data) = match message.kind {
+            Kind::Register => (
+                &b"register"[..],
+                message.get(protocol::PHRASE).unwrap_or_default(),
+            ),
+            Kind::Status => (&b"status"[..], &[][..]),
+            Kind::Nameplate => (&b"nameplate"[..], &[][..]),
+            _ => return None,
+        };
+        if let Some(tokens) = &state.tokens {
+            if !message
+                .get(protocol::CREDENTIAL)
+                .is_some_and(|credential| tokens.valid(credential, purpose, data))
+            {
+                Metrics::count(&state.metrics.unauthenticated);
+                return None;
+            }
+        }
+        if message.kind == Kind::Status {
+            // without the comments, so the answer fits into the request
+            let metrics: String = render(state)
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+                .map(|line| line.to_owned() + "\n")
+                .collect();
+            let status = Message::new(Kind::Status).with(protocol::METRICS, metrics.as_bytes());
+            guard::reply(listener, &status.encode(), l, addr);
+            return None;
+        }
+        if message.kind == Kind::Nameplate {
+            let reply = match state
+                .nameplates
+                .lock()
+                .unwrap()
+                .allocate(canonical_addr(addr).ip())
+            {
+                Some(nameplate) => Message::new(Kind::Nameplate)
+                    .with(protocol::NAMEPLATE, &nameplate.to_be_bytes()),
+                None => Message::error(
+                    protocol::TOO_MANY_WAITING,
+                    "no free nameplates left, or too many taken from your IP",
+                ),
+            };
+            guard::reply(listener, &reply.encode(), l, addr);
+            return None;
+        }
+        if message.version != protocol::VERSION {
+            Metrics::count(&state.metrics.other_registrations);
+            let error = Message::error(
+                protocol::UNSUPPORTED_VERSION,
+                "this helper only speaks version 2",
+            );
+            guard::reply(listener, &error.encode(), l, addr);
+            return None;
+        }
+        let phrase = match message.get(protocol::PHRASE) {
+            Some(phrase) if phrase.len() <= protocol::MAX_PHRASE => phrase,
+            _ => {
+                let error =
+                    Message::error(protocol::BAD_REQUEST, "the phrase is missing or too long");
+                guard::reply(listener, &error.encode(), l, addr);
+                return None;
+            }
+        };
+        if let Some(cookies) = &state.cookies {
+            let cookies = cookies.get(&addr);
+            if !message
+                .get(protocol::COOKIE)
+                .is_some_and(|cookie| cookies.valid(cookie, addr))
+            {
+                let error = Message::error(
+                    protocol::COOKIE_REQUIRED,
+                    "send the cookie back to register",
+                )
+                .with(protocol::COOKIE, &cookies.current(addr));
+                guard::reply(listener, &error.encode(), l, addr);
+                return None;
+            }
+        }
+        return Some(Registration {
+            addr,
+            key: pairing::key(phrase, addr, via),
+            protocol: Protocol::V2,
+            role: message.role(),
+            file_size: message.file_size(),
+            candidates: message.get(protocol::CANDIDATES).map(|c| c.to_vec()),
+            sent: l,
+            nameplate: message.nameplate(),
+        });
+    }
+
+    // the legacy protocol can't carry a credential
+    if state.tokens.is_some() {
+        return None;
+    }
+    if let Some(cookies) = &state.cookies {
+        if cookies.get(&addr).check(packet, addr) {
+            return None;
+        }
+    }
+    if l > CANDIDATES_MAGIC.len() && packet.starts_with(CANDIDATES_MAGIC) {
+        let list = &packet[CANDIDATES_MAGIC.len()..];
+        state.candidates.get(&addr).insert(addr, list, l);
+        return None;
+    }
+    if l != 200 {
+        return None;
+    }
+    if let Some(cookies) = &state.cookies {
+        let mut cookies = cookies.get(&addr);
+        if !cookies.verified(addr) {
+            let _ = listener.send_to(&cookies.challenge(addr), addr);
+            return None;
+        }
+    }
+    let candidates = state.candidates.get(&addr).take(addr);
+    let (candidates, sent) = match candidates {
+        Some((list, sent)) => (Some(list), sent + 200),
+        None => (None, 200),
+    };
+    Some(Registration {
+        addr,
+        key: pairing::key(packet, addr, via),
+        protocol: Protocol::Legacy,
+        role: None,
+        file_size: None,
+        candidates,
+        sent,
+        nameplate: None,
+    })
+}
+
+fn register(listener: &UdpSocket, state: &State, registration: Registration) {
+    let (addr, l) = (registration.addr, registration.sent);
+    let v2 = registration.protocol == Protocol::V2;
+    Metrics::count(if v2 {
+        &state.metrics.v2_registrations
+    } else {
+        &state.metrics.legacy_registrations
+    });
+    let nameplate = registration.nameplate;
+    let outcome = state
+        .pairings
+        .get(&registration.key)
+        .register(&state.sockets, registration);
+    match &outcome {
+        Outcome::Waiting => {
+            if let Some(nameplate) = nameplate {
+                state
+                    .nameplates
+                    .lock()
+                    .unwrap()
+                    .renew(nameplate, canonical_addr(addr).ip());
+            }
+        }
+        // whichever of the two asked for the code
+        Outcome::Paired(pair) => {
+            let mut nameplates = state.nameplates.lock().unwrap();
+            if let Some(nameplate) = pair.nameplates.0 {
+                nameplates.release(nameplate, canonical_addr(addr).ip());
+            }
+            if let Some(nameplate) = pair.nameplates.1 {
+                nameplates.release(nameplate, canonical_addr(pair.partner).ip());
+            }
+        }
+        _ => (),
+    }
+    let pair = match outcome {
+        Outcome::Paired(pair) => pair,
+        // older clients don't expect an answer before the partner is there
+        Outcome::Waiting if v2 => {
+            let waiting = Message::new(Kind::Waiting).encode();
+            guard::reply(listener, &waiting, l, addr);
+            return;
+        }
+        Outcome::Refused if v2 => {
+            let error = Message::error(
+                protocol::TOO_MANY_WAITING,
+                "too many peers from your IP are waiting",
+            );
+            guard::reply(listener, &error.encode(), l, addr);
+            return;
+        }
+        Outcome::Conflict(waiting) => {
+            let error = Message::error(
+                protocol::ROLE_CONFLICT,
+                &format!(
+                    "a {} is waiting with this phrase, which needs a {}",
+                    waiting.name(),
+                    waiting.partner().name()
+                ),
+            );
+            guard::reply(listener, &error.encode(), l, addr);
+            return;
+        }
+        _ => return,
+    };
+    let other = &pair.partner;
+    // we got a connection
+    let (time, punch_at) = punch::Schedule::plan();
+    let addr_buf = protocol::Paired {
+        partner: canonical_addr(addr),
+        candidates: pair.candidates.0.unwrap_or_default(),
+        schedule: Some((time, punch_at)),
+        file_size: pair.file_sizes.0,
+    }
+    .encode(pair.protocols.1, pair.sent.1);
+    let other_buf = protocol::Paired {
+        partner: canonical_addr(*other),
+        candidates: pair.candidates.1.unwrap_or_default(),
+        schedule: Some((time, punch_at)),
+        file_size: pair.file_sizes.1,
+    }
+    .encode(pair.protocols.0, pair.sent.0);
+    if listener.send_to(&addr_buf, other).is_ok() && listener.send_to(&other_buf, addr).is_ok() {
+        if state.relaying {
+            state.relay.lock().unwrap().paired(addr, *other);
+        }
+        // success!
+        Metrics::count(&state.metrics.pairings);
+        let _ = state.log.try_send((addr, *other));
+    }
+}
+
+/// The metrics, with the counts which each shard of the pairings keeps.
+fn render(state: &State) -> String {
+    let (mut waiting, mut expired) = (0, 0);
+    state.pairings.each(|pairings| {
+        waiting += pairings.waiting() as u64;
+        expired += pairings.expired();
+    });
+    state.metrics.render(waiting, expired)
+}
+
+/// Prints the pairings and counts them in the log, away from the threads which pair. The log is
+/// the file at `path`, or stdout if that is `-`.
+fn write_log(pairings: Receiver<(SocketAddr, SocketAddr)>, path: &str, interval: u64) {
+    let mut last_log_time = unix_millis();
+    let mut amount_since_log = 0;
+    let mut helper_log: Box<dyn Write> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("unable to create helper log"),
+        )
+    };
+    for (addr, other) in pairings {
+        println!(
+            "Helped {} and {}! :D",
+            canonical_addr(addr),
+            canonical_addr(other)
+        );
+        amount_since_log += 1;
+        if unix_millis() - last_log_time > interval * 1000 {
+            let d = PrimitiveDateTime::new(
+                Date::from_calendar_date(1970, time::Month::January, 1).unwrap(),
+                Time::MIDNIGHT,
+            ) + Duration::from_millis(unix_millis());
+            helper_log
+                .write_all(
+                    format!(
+                        "{} | {} {}>\n",
+                        d,
+                        amount_since_log,
+                        amount_since_log * Wrap("=")
+                    )
+                    .as_bytes(),
+                )
+                .expect("error writing to log");
+            helper_log.flush().expect("error writing to log");
+            last_log_time = unix_millis();
+            amount_since_log = 0;
+        }
+    }
+}
+
+/// Asks a helper for its metrics and prints them.
+pub fn status(args: &[String]) -> Result<(), HolepunchError> {
+    let Some(helper) = args.get(2) else {
+        print_args(args);
+        return Ok(());
+    };
+    let addr = helper
+        .to_socket_addrs()
+        .expect("invalid helper address")
+        .next()
+        .expect("invalid helper address");
+    let socket = UdpSocket::bind(if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })
+    .expect("unable to create socket");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let token = env::var("QFT_HELPER_TOKEN").ok();
+    let mut buf = [0u8; 2048];
+    for _ in 0..3 {
+        // a new credential each time, helpers accept each one only once
+        let mut request = Message::new(Kind::Status);
+        if let Some(token) = &token {
+            let credential = guard::credential(token.as_bytes(), b"status", &[]);
+            request = request.with(protocol::CREDENTIAL, &credential);
+        }
+        // padded, because the helper doesn't answer with more than it got
+        let request = request.encode_padded(protocol::REGISTER_SIZE);
+        let _ = socket.send_to(&request, addr);
+        while let Ok(l) = socket.recv(&mut buf) {
+            if let Some(status) = Message::decode(&buf[..l]).filter(|m| m.kind == Kind::Status) {
+                let metrics = status.get(protocol::METRICS).unwrap_or_default();
+                print!("{}", String::from_utf8_lossy(metrics));
+                return Ok(());
+            }
+        }
+    }
+    Err(HolepunchError::HelperUnreachable(helper.clone()))
+}
+
+/// Measures how many pairings a helper manages per second: `threads` pairs of peers register
+/// random phrases, each pair as soon as the last one was paired. All of them come from one IP, so
+/// the helper needs `--rate-limit 0`.
+pub fn bench(args: &[String]) {
+    let helper = args
+        .get(2)
+        .unwrap_or_else(|| {
+            print_args(args);
+            panic!("unreachable")
+        })
+        .to_socket_addrs()
+        .expect("invalid helper address")
+        .next()
+        .expect("invalid helper address");
+    let pairings = args
+        .get(3)
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("invalid pairing count: must be integer")
+        })
+        .unwrap_or(10_000);
+    let threads = args
+        .get(4)
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("invalid thread count: must be integer")
+        })
+        .unwrap_or(8)
+        .max(1);
+    println!(
+        "Pairing {} times with {} threads at {}...",
+        pairings, threads, helper
+    );
+    let start = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|i| {
+            let count = pairings / threads + usize::from(i < pairings % threads);
+            thread::spawn(move || bench_pairs(helper, count))
+        })
+        .collect();
+    let mut latencies = vec![];
+    let mut failed = 0;
+    for worker in workers {
+        let (done, lost) = worker.join().unwrap();
+        latencies.extend(done);
+        failed += lost;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    latencies.sort();
+    println!(
+        "{} pairings in {:.2}s: {:.0} pairings/s, {} failed.",
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed,
+        failed
+    );
+    if !latencies.is_empty() {
+        let at = |fraction: f64| latencies[((latencies.len() - 1) as f64 * fraction) as usize];
+        println!(
+            "Latency: median {} µs, 99% {} µs, max {} µs.",
+            at(0.5),
+            at(0.99),
+            at(1.0)
+        );
+    }
+    if failed > 0 {
+        println!("Pairings fail if the helper doesn't run with --rate-limit 0.");
+    }
+}
+
+/// Pairs `count` times, and returns how long each pairing took in microseconds and how many
+/// failed.
+fn bench_pairs(helper: SocketAddr, count: usize) -> (Vec<u64>, usize) {
+    let bind = if helper.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let sender = UdpSocket::bind(bind).expect("unable to create socket");
+    let receiver = UdpSocket::bind(bind).expect("unable to create socket");
+    for socket in [&sender, &receiver] {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+    }
+    let mut latencies = vec![];
+    let mut failed = 0;
+    for i in 0..count {
+        let phrase = format!("bench {} {:x}", i, nonce());
+        let register = |role: Role| {
+            Message::new(Kind::Register)
+                .with(protocol::PHRASE, phrase.as_bytes())
+                .with(protocol::ROLE, &[role.id()])
+                .encode_padded(protocol::REGISTER_SIZE)
+        };
+        let start = Instant::now();
+        let _ = sender.send_to(&register(Role::Sender), helper);
+        let _ = receiver.send_to(&register(Role::Receiver), helper);
+        if bench_paired(&sender) && bench_paired(&receiver) {
+            latencies.push(start.elapsed().as_micros() as u64);
+        } else {
+            failed += 1;
+        }
+    }
+    (latencies, failed)
+}
+
+fn bench_paired(socket: &UdpSocket) -> bool {
+    let mut buf = [0u8; 2048];
+    while let Ok(l) = socket.recv(&mut buf) {
+        if Message::decode(&buf[..l]).is_some_and(|m| m.kind == Kind::Paired) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// What `qft helper <flags>` ends up with for the config file `text`: the arguments and the
+    /// ports.
+    fn configured(name: &str, flags: &[&str], text: &str) -> (Vec<String>, [Option<String>; 2]) {
+        let path = env::temp_dir().join(format!("qft-helper-{}-{}.toml", process::id(), name));
+        fs::write(&path, text).unwrap();
+        let mut args: Vec<String> = ["qft", "helper"]
+            .iter()
+            .chain(flags)
+            .map(|a| a.to_string())
+            .collect();
+        let ports = apply_config(&mut args, path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        (args, ports)
+    }
+
+    #[test]
+    fn relay_limits_without_relay() {
+        let text = "port = 24278\nrelay = false\nrelay-rate = 100\n";
+        let (mut args, ports) = configured("off", &[], text);
+        assert_eq!(ports[0].as_deref(), Some("24278"));
+        assert!(relay_limits(&mut args).is_err());
+        // nothing is left which could be taken for the port
+        assert_eq!(args, ["qft", "helper"]);
+    }
+
+    #[test]
+    fn relay_limits_with_relay() {
+        let text = "relay = true\nrelay-rate = 100\n";
+        let (mut args, _) = configured("on", &[], text);
+        let limits = relay_limits(&mut args).unwrap().unwrap();
+        assert_eq!(limits.rate, 100 * 1024);
+        assert_eq!(limits.quota, 1024 * 1024 * 1024);
+        assert_eq!(args, ["qft", "helper"]);
+        // flags win over the config file
+        let (mut args, _) = configured("flag", &["--relay-rate", "200"], text);
+        assert_eq!(relay_limits(&mut args).unwrap().unwrap().rate, 200 * 1024);
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    str::FromStr,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use std::fs;
+
+use crate::{predict::Mapping, unix_millis};
+
+/// Sent to the helper before registering, so the helper can pass our candidates on to the partner.
+pub const CANDIDATES_MAGIC: &[u8] = b"qft-candidates\n";
+/// Candidate messages are padded to this size with empty lines. The helper's answer can't be bigger
+/// than what we sent, and has to make room for the partner's candidates.
+pub const CANDIDATES_SIZE: usize = 1024;
+const CHECK_MAGIC: &[u8] = b"qft-check";
+
+// kinds of check packets: [CHECK_MAGIC][kind: u8][tag: u64 BE]
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const NOMINATE: u8 = 2;
+const NOMINATE_ACK: u8 = 3;
+const CONFIRM: u8 = 4;
+/// How long the confirmation of a chosen pair is repeated.
+const CONFIRM_LINGER_MS: u64 = 250;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CandidateKind {
+    /// An address of one of our own network interfaces.
+    Host,
+    /// An address a router mapped for us on request.
+    Mapped,
+    /// An address we learned because the partner's check came from it.
+    PeerReflexive,
+    /// Our public address, as seen by the helper.
+    ServerReflexive,
+    /// An address on a relay, which forwards everything to us.
+    Relayed,
+}
+use CandidateKind::*;
+
+impl CandidateKind {
+    fn preference(self) -> u32 {
+        match self {
+            Host => 126,
+            Mapped => 115,
+            PeerReflexive => 110,
+            ServerReflexive => 100,
+            Relayed => 0,
+        }
+    }
+
+    fn letter(self) -> char {
+        match self {
+            Host => 'h',
+            Mapped => 'm',
+            PeerReflexive => 'p',
+            ServerReflexive => 's',
+            Relayed => 'r',
+        }
+    }
+
+    fn from_letter(letter: &str) -> Option<CandidateKind> {
+        Some(match letter {
+            "h" => Host,
+            "m" => Mapped,
+            "p" => PeerReflexive,
+            "s" => ServerReflexive,
+            "r" => Relayed,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+}
+
+impl Candidate {
+    /// Higher is better. IPv6 wins over IPv4 of the same kind.
+    pub fn priority(&self) -> u32 {
+        (self.kind.preference() << 8) | self.addr.is_ipv6() as u32
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}", self.kind, self.addr)
+    }
+}
+
+/// What one peer tells the other through the helper. The tie breaker decides which peer picks the
+/// final candidate pair (the higher one does).
+pub struct CandidateList {
+    pub tie_breaker: u64,
+    pub candidates: Vec<Candidate>,
+    /// Set if the peer is behind a symmetric NAT and measured how it picks ports.
+    pub mapping: Option<Mapping>,
+    /// Whether the peer agrees to a relay through the helper if nothing else works.
+    pub allow_relay: bool,
+    /// Whether the peer knows the holepunch of punch.rs. Older ones only know the legacy ones.
+    pub punch: bool,
+}
+
+impl CandidateList {
+    /// One candidate per line, human-readable like the rest of the helper protocol.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut s = format!("t {}\n", self.tie_breaker);
+        if let Some(mapping) = &self.mapping {
+            s += mapping.encode().as_str();
+        }
+        let mut options = vec![];
+        if self.allow_relay {
+            options.push("relay");
+        }
+        if self.punch {
+            options.push("punch");
+        }
+        if !options.is_empty() {
+            s += format!("o {}\n", options.join(" ")).as_str();
+        }
+        for c in &self.candidates {
+            s += format!("{} {}\n", c.kind.letter(), c.addr).as_str();
+        }
+        s.into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<CandidateList> {
+        let s = String::from_utf8_lossy(bytes);
+        let mut list = CandidateList {
+            tie_breaker: 0,
+            candidates: vec![],
+            mapping: None,
+            allow_relay: false,
+            punch: false,
+        };
+        let mut has_tie_breaker = false;
+        for line in s.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            if key == "t" {
+                list.tie_breaker = u64::from_str(value).ok()?;
+                has_tie_breaker = true;
+            } else if key == "n" {
+                list.mapping = Mapping::decode(value);
+            } else if key == "o" {
+                for option in value.split(' ') {
+                    match option {
+                        "relay" => list.allow_relay = true,
+                        "punch" => list.punch = true,
+                        _ => (),
+                    }
+                }
+            } else if let (Some(kind), Ok(addr)) =
+                (CandidateKind::from_letter(key), SocketAddr::from_str(value))
+            {
+                list.candidates.push(Candidate { kind, addr });
+            }
+        }
+        has_tie_breaker.then_some(list)
+    }
+}
+
+/// Identifies the check packets of one session, so two sessions sharing a network can't confuse
+/// each other. (FNV-1a of the phrase)
+pub fn phrase_tag(phrase: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in phrase {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Lists the addresses of all our network interfaces which can reach `helper`'s address family,
+/// combined with the port of `socket`.
+pub fn host_candidates(socket: &UdpSocket, helper: SocketAddr) -> Vec<Candidate> {
+    let port = socket.local_addr().unwrap().port();
+    let mut ips: Vec<IpAddr> = vec![];
+    // the address of the interface which leads to the helper comes first
+    let probe_bind: SocketAddr = if helper.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    if let Ok(probe) = UdpSocket::bind(probe_bind) {
+        if probe.connect(helper).is_ok() {
+            ips.push(probe.local_addr().unwrap().ip());
+        }
+    }
+    for ip in interface_ips() {
+        if ip.is_ipv6() == helper.is_ipv6() && !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips.retain(|ip| !ip.is_loopback() && !ip.is_unspecified() && !is_link_local(ip));
+    ips.into_iter()
+        .map(|ip| Candidate {
+            kind: Host,
+            addr: SocketAddr::new(ip, port),
+        })
+        .collect()
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        // link-local IPv6 addresses need a scope id, which can't be sent to the partner
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// The standard library can't list interfaces, so this reads what Linux shows in /proc. Elsewhere,
+/// only the interface leading to the helper is known.
+#[cfg(target_os = "linux")]
+fn interface_ips() -> Vec<IpAddr> {
+    let mut ips = vec![];
+    // IPv4: every "/32 host LOCAL" line names the address in the line before it
+    if let Ok(trie) = fs::read_to_string("/proc/net/fib_trie") {
+        let mut last: Option<Ipv4Addr> = None;
+        for line in trie.lines() {
+            if line.contains("/32 host LOCAL") {
+                if let Some(ip) = last {
+                    if !ips.contains(&IpAddr::V4(ip)) {
+                        ips.push(IpAddr::V4(ip));
+                    }
+                }
+            }
+            last = line
+                .trim_start_matches(|c: char| c.is_whitespace() || c == '|' || c == '+' || c == '-')
+                .parse()
+                .ok();
+        }
+    }
+    // IPv6: the first column is the address as 32 hex digits
+    if let Ok(list) = fs::read_to_string("/proc/net/if_inet6") {
+        for line in list.lines() {
+            let Some(hex) = line.split_whitespace().next() else {
+                continue;
+            };
+            if let Ok(n) = u128::from_str_radix(hex, 16) {
+                ips.push(IpAddr::V6(Ipv6Addr::from(n)));
+            }
+        }
+    }
+    ips
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_ips() -> Vec<IpAddr> {
+    vec![]
+}
+
+fn check_packet(kind: u8, tag: u64) -> Vec<u8> {
+    let mut packet = Vec::from(CHECK_MAGIC);
+    packet.push(kind);
+    packet.extend_from_slice(&tag.to_be_bytes());
+    packet
+}
+
+/// Sends checks to all of the partner's candidates at once and picks the best pair that works in
+/// both directions. The peer with the higher tie breaker decides which pair is used, the other one
+/// follows. Neither uses the pair before the deciding peer got the acknowledgement of its choice
+/// and confirmed it, so both either use it or go on to holepunching. The first packets the partner
+/// sends over the pair once it uses it are left in the socket for the caller. Returns None if
+/// nothing worked before the timeout.
+pub fn connect(
+    socket: &UdpSocket,
+    tag: u64,
+    tie_breaker: u64,
+    remote: &CandidateList,
+    timeout: Duration,
+) -> Option<SocketAddr> {
+    if tie_breaker == remote.tie_breaker {
+        return None;
+    }
+    let controlling = tie_breaker > remote.tie_breaker;
+    let mut candidates = remote.candidates.clone();
+    candidates.sort_by_key(|c| Reverse(c.priority()));
+    let mut working: HashSet<SocketAddr> = HashSet::new();
+    let mut nominated: Option<SocketAddr> = None;
+    let mut confirmed_at = None;
+    let mut first_success = 0;
+    let mut last_send = 0;
+
+    let start = unix_millis();
+    let mut deadline = start + timeout.as_millis() as u64;
+    socket
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    // big enough for any packet, so they can be looked at without being taken
+    let mut buf = vec![0u8; 0x10000];
+    while unix_millis() < deadline {
+        // the confirmations are spread out a little, so a short burst of loss doesn't eat them all
+        if confirmed_at.is_some_and(|at| unix_millis() - at >= CONFIRM_LINGER_MS) {
+            return nominated;
+        }
+        if unix_millis() - last_send >= 50 {
+            last_send = unix_millis();
+            match nominated {
+                Some(addr) if confirmed_at.is_some() => {
+                    let _ = socket.send_to(&check_packet(CONFIRM, tag), addr);
+                }
+                Some(addr) if controlling => {
+                    let _ = socket.send_to(&check_packet(NOMINATE, tag), addr);
+                }
+                Some(_) => (),
+                None => {
+                    for c in &candidates {
+                        if !working.contains(&c.addr) {
+                            let _ = socket.send_to(&check_packet(REQUEST, tag), c.addr);
+                        }
+                    }
+                }
+            }
+        }
+
+        // leave the partner time to acknowledge before either of us gives up
+        if controlling
+            && nominated.is_none()
+            && !working.is_empty()
+            && deadline.saturating_sub(unix_millis()) > CONFIRM_LINGER_MS * 2
+        {
+            // the best candidate works, or better ones had their chance
+            let best = candidates.iter().find(|c| working.contains(&c.addr));
+            if best.map(|c| c.addr) == candidates.first().map(|c| c.addr)
+                || unix_millis() - first_success > 500
+            {
+                let best = best.unwrap();
+                eprintln!("Using {} of the partner.", best);
+                nominated = Some(best.addr);
+                last_send = 0;
+            }
+        }
+
+        let Ok((len, from)) = socket.peek_from(&mut buf) else {
+            continue;
+        };
+        let is_check = len == CHECK_MAGIC.len() + 9 && &buf[..CHECK_MAGIC.len()] == CHECK_MAGIC;
+        // The partner uses the pair already, which the controlled side only does after the
+        // confirmation, and the controlling side after the acknowledgement. Its data is for the
+        // caller.
+        if !is_check && nominated == Some(from) && (confirmed_at.is_some() || !controlling) {
+            return nominated;
+        }
+        let _ = socket.recv_from(&mut buf);
+        if !is_check || buf[CHECK_MAGIC.len() + 1..len] != tag.to_be_bytes() {
+            continue;
+        }
+        match buf[CHECK_MAGIC.len()] {
+            REQUEST => {
+                let _ = socket.send_to(&check_packet(RESPONSE, tag), from);
+                if !candidates.iter().any(|c| c.addr == from) {
+                    candidates.push(Candidate {
+                        kind: PeerReflexive,
+                        addr: from,
+                    });
+                    candidates.sort_by_key(|c| Reverse(c.priority()));
+                }
+            }
+            RESPONSE => {
+                if working.is_empty() {
+                    first_success = unix_millis();
+                }
+                working.insert(from);
+            }
+            NOMINATE if !controlling => {
+                let _ = socket.send_to(&check_packet(NOMINATE_ACK, tag), from);
+                if nominated != Some(from) {
+                    eprintln!("Partner chose {}.", from);
+                    // the partner only confirms once it has our acknowledgement, wait for that
+                    // even if our own time is up
+                    deadline = deadline.max(unix_millis() + CONFIRM_LINGER_MS * 4);
+                }
+                nominated = Some(from);
+            }
+            // from here on the partner uses the pair as soon as one confirmation arrives
+            NOMINATE_ACK if controlling && nominated == Some(from) && confirmed_at.is_none() => {
+                confirmed_at = Some(unix_millis());
+                last_send = 0;
+            }
+            CONFIRM if !controlling && nominated == Some(from) => return nominated,
+            _ => (),
+        }
+    }
+    // without a confirmation the partner didn't get to use the pair either
+    confirmed_at.and(nominated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn list(socket: &UdpSocket, tie_breaker: u64) -> CandidateList {
+        CandidateList {
+            tie_breaker,
+            candidates: vec![Candidate {
+                kind: Host,
+                addr: socket.local_addr().unwrap(),
+            }],
+            mapping: None,
+            allow_relay: false,
+            punch: true,
+        }
+    }
+
+    /// The next packet which isn't a late check.
+    fn data(socket: &UdpSocket) -> Vec<u8> {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut buf = [0u8; 64];
+        loop {
+            let len = socket.recv(&mut buf).expect("the partner's data got lost");
+            if !buf.starts_with(CHECK_MAGIC) {
+                return buf[..len].to_vec();
+            }
+        }
+    }
+
+    #[test]
+    fn both_sides_get_the_first_packets() {
+        let controlled = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let controlling = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (to_controlled, to_controlling) = (list(&controlled, 1), list(&controlling, 2));
+        let tag = phrase_tag(b"ice test");
+        let partner = thread::spawn(move || {
+            let addr = connect(&controlling, tag, 2, &to_controlled, Duration::from_secs(5));
+            controlling.connect(addr.unwrap()).unwrap();
+            controlling.send(b"from the controlling side").unwrap();
+            controlling
+        });
+        let addr = connect(&controlled, tag, 1, &to_controlling, Duration::from_secs(5));
+        controlled.connect(addr.unwrap()).unwrap();
+        // right away, while the partner may still repeat its confirmation
+        controlled.send(b"from the controlled side").unwrap();
+        let controlling = partner.join().unwrap();
+        assert_eq!(data(&controlling), b"from the controlled side");
+        assert_eq!(data(&controlled), b"from the controlling side");
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{os_random, sha256, unix_millis};
+
+/// Where peers announce themselves. The first qft on a machine listens here, later ones can still
+/// be found because their announcements are answered directly.
+pub const DISCOVERY_PORT: u16 = 4278;
+const MAGIC: &[u8] = b"qft-lan";
+
+// [MAGIC][kind: u8][token: u64 BE][nonce: u64 BE]
+const ANNOUNCE: u8 = 0;
+const FOUND: u8 = 1;
+const FOUND_ACK: u8 = 2;
+
+/// The phrase itself is never broadcast, only this MAC with it. The sender's random nonce is the
+/// salt, so a token can't be looked up in a table made in advance, and doesn't show that two
+/// discoveries used the same phrase. Short phrases can still be guessed by trying.
+fn token(phrase: &[u8], nonce: u64) -> u64 {
+    let mut data = Vec::from(MAGIC);
+    data.extend_from_slice(&nonce.to_be_bytes());
+    u64::from_be_bytes(sha256::hmac_sha256(phrase, &data)[..8].try_into().unwrap())
+}
+
+fn packet(kind: u8, phrase: &[u8], nonce: u64) -> Vec<u8> {
+    let mut packet = Vec::from(MAGIC);
+    packet.push(kind);
+    packet.extend_from_slice(&token(phrase, nonce).to_be_bytes());
+    packet.extend_from_slice(&nonce.to_be_bytes());
+    packet
+}
+
+/// Returns the kind and nonce of a discovery packet made with the same phrase.
+fn parse(buf: &[u8], phrase: &[u8]) -> Option<(u8, u64)> {
+    if buf.len() != MAGIC.len() + 17 || &buf[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let buf = &buf[MAGIC.len()..];
+    let nonce = u64::from_be_bytes(buf[9..17].try_into().unwrap());
+    if !sha256::equal(&buf[1..9], &token(phrase, nonce).to_be_bytes()) {
+        return None;
+    }
+    Some((buf[0], nonce))
+}
+
+/// Looks for a partner with the same phrase in the local network, using UDP broadcasts. Returns a
+/// socket connected to the partner, or None if nobody answered in time.
+pub fn discover(phrase: &[u8], timeout: Option<Duration>) -> Option<UdpSocket> {
+    let mut nonce = [0u8; 8];
+    os_random(&mut nonce);
+    let nonce = u64::from_be_bytes(nonce);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).expect("unable to create socket");
+    socket.set_broadcast(true).expect("unable to broadcast");
+    socket
+        .set_read_timeout(Some(Duration::from_millis(20)))
+        .unwrap();
+    // only one program per machine can have the discovery port, that's fine
+    let listener = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).ok();
+    if let Some(listener) = &listener {
+        listener.set_nonblocking(true).unwrap();
+    }
+    eprintln!("Looking for the partner in the local network...");
+
+    let start = unix_millis();
+    let mut last_announce = 0;
+    let mut partner: Option<SocketAddr> = None;
+    let mut connected = false;
+    let mut buf = [0u8; 64];
+    while timeout.is_none() || unix_millis() - start < timeout.unwrap().as_millis() as u64 {
+        if unix_millis() - last_announce >= 500 {
+            last_announce = unix_millis();
+            match partner {
+                // keep telling the partner until it acknowledges
+                Some(addr) => {
+                    let _ = socket.send_to(&packet(FOUND, phrase, nonce), addr);
+                }
+                None => {
+                    let _ = socket.send_to(
+                        &packet(ANNOUNCE, phrase, nonce),
+                        (Ipv4Addr::BROADCAST, DISCOVERY_PORT),
+                    );
+                }
+            }
+        }
+
+        // announcements of others, answered from our own socket so they learn its address
+        if let Some(listener) = &listener {
+            while let Ok((len, from)) = listener.recv_from(&mut buf) {
+                match parse(&buf[..len], phrase) {
+                    Some((ANNOUNCE, n)) if n != nonce && partner.is_none() => {
+                        let _ = socket.send_to(&packet(FOUND, phrase, nonce), from);
+                        partner = Some(from);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        match parse(&buf[..len], phrase) {
+            Some((FOUND, n)) if n != nonce && (partner.is_none() || partner == Some(from)) => {
+                for _ in 0..3 {
+                    let _ = socket.send_to(&packet(FOUND_ACK, phrase, nonce), from);
+                }
+                partner = Some(from);
+                connected = true;
+                break;
+            }
+            Some((FOUND_ACK, n)) if n != nonce && partner == Some(from) => {
+                connected = true;
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    if !connected {
+        return None;
+    }
+    let partner = partner.unwrap();
+    eprintln!("Found the partner at {}.", partner);
+    socket.set_broadcast(false).unwrap();
+    socket.connect(partner).expect("connection failed");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    socket
+        .set_write_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    Some(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_own_packets() {
+        let packet = packet(FOUND, b"lan phrase", 42);
+        assert_eq!(packet.len(), MAGIC.len() + 17);
+        assert_eq!(parse(&packet, b"lan phrase"), Some((FOUND, 42)));
+    }
+
+    #[test]
+    fn rejects_other_phrases_and_garbage() {
+        let packet = packet(ANNOUNCE, b"lan phrase", 42);
+        assert_eq!(parse(&packet, b"other phrase"), None);
+        assert_eq!(parse(&packet[..packet.len() - 1], b"lan phrase"), None);
+        let mut forged = packet.clone();
+        forged[MAGIC.len() + 16] ^= 1;
+        assert_eq!(parse(&forged, b"lan phrase"), None);
+        let mut foreign = packet;
+        foreign[0] = b'x';
+        assert_eq!(parse(&foreign, b"lan phrase"), None);
+    }
+
+    #[test]
+    fn tokens_depend_on_the_nonce() {
+        assert_ne!(token(b"lan phrase", 1), token(b"lan phrase", 2));
+        assert_ne!(token(b"lan phrase", 1), token(b"other phrase", 1));
+    }
+}
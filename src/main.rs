@@ -1,19 +1,44 @@
 #[cfg(feature = "gui")]
 mod gui;
 
+mod config;
+mod direct;
+mod doctor;
+mod guard;
+mod helper;
+mod ice;
+mod lan;
+mod metrics;
+mod mux;
+mod pairing;
+mod pipe;
+mod portmap;
+mod predict;
+mod protocol;
+mod punch;
+mod relay;
+mod sha256;
+mod shard;
+mod stun;
+mod tunnel;
+mod words;
+
 use std::{
-    collections::HashMap,
-    env,
-    fs::{File, OpenOptions},
+    collections::{HashMap, VecDeque},
+    env, fmt,
+    fs::{self, File, OpenOptions},
     io::{stdout, Error, Read, Seek, SeekFrom, Write},
     net::*,
     ops::Mul,
-    str::FromStr,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
     thread,
     time::{Duration, SystemTime},
 };
 
-use time::{Date, PrimitiveDateTime, Time};
+use ice::{Candidate, CandidateKind, CandidateList, CANDIDATES_MAGIC, CANDIDATES_SIZE};
+use predict::Mapping;
+use protocol::Role;
 
 #[derive(Ord, Eq, PartialOrd, PartialEq)]
 enum SafeReadWritePacket {
@@ -24,11 +49,36 @@ enum SafeReadWritePacket {
 }
 use SafeReadWritePacket::*;
 
+/// Set by modes which write their data to stdout (pipe), so status messages go to stderr there.
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+
+/// Prints a status message like println!, to stdout unless that carries data.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        print_status(format_args!($($arg)*), true)
+    };
+}
+
+fn print_status(message: fmt::Arguments, newline: bool) {
+    let newline = if newline { "\n" } else { "" };
+    if STDOUT_IS_DATA.load(Ordering::Relaxed) {
+        eprint!("{}{}", message, newline);
+    } else {
+        print!("{}{}", message, newline);
+    }
+}
+
 struct SafeReadWrite {
     socket: UdpSocket,
     last_transmitted: HashMap<u16, Vec<u8>>,
     packet_count_out: u64,
     packet_count_in: u64,
+    // packets the partner sent while we were writing (only happens in duplex use). None marks End.
+    received: VecDeque<Option<Vec<u8>>>,
+    // for the answers to writes, large enough for the partner's data packets in duplex use
+    reply_buf: Vec<u8>,
+    // when try_read_safe last sent unacknowledged packets again
+    resent_at: u64,
 }
 
 struct Wrap<T>(T);
@@ -49,6 +99,9 @@ impl SafeReadWrite {
             last_transmitted: HashMap::new(),
             packet_count_in: 0,
             packet_count_out: 0,
+            received: VecDeque::new(),
+            reply_buf: vec![0; 0xffff],
+            resent_at: unix_millis(),
         }
     }
 
@@ -61,6 +114,43 @@ impl SafeReadWrite {
     }
 
     pub fn read_safe(&mut self, buf: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+        loop {
+            if let Some(r) = self.internal_read_safe(buf, true)? {
+                return Ok(r);
+            }
+        }
+    }
+
+    /// Like read_safe, but gives up after waiting `timeout` for a packet. Used when both partners
+    /// read and write at the same time (duplex use). Nobody waits for the Acks of our writes then,
+    /// so packets still unacknowledged are sent again every second, in case the last ones got lost.
+    pub fn try_read_safe(
+        &mut self,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> Result<Option<(Vec<u8>, usize)>, Error> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        let r = self.internal_read_safe(buf, false)?;
+        if r.is_none() && unix_millis() - self.resent_at > 1000 {
+            self.resent_at = unix_millis();
+            let latest = (self.packet_count_out as u16).wrapping_sub(1);
+            let oldest = self
+                .last_transmitted
+                .keys()
+                .max_by_key(|id| latest.wrapping_sub(**id));
+            if let Some(oldest) = oldest.copied() {
+                let id = oldest.to_be_bytes();
+                self.handle_control(&[id[0], id[1], ResendRequest as u8]);
+            }
+        }
+        Ok(r)
+    }
+
+    fn internal_read_safe(
+        &mut self,
+        buf: &[u8],
+        blocking: bool,
+    ) -> Result<Option<(Vec<u8>, usize)>, Error> {
         if buf.len() > 0xfffc {
             panic!(
                 "attempted to receive too large data packet with SafeReadWrite ({} > 0xfffc)",
@@ -68,6 +158,16 @@ impl SafeReadWrite {
             );
         }
 
+        if let Some(packet) = self.received.pop_front() {
+            return Ok(Some(match packet {
+                Some(data) => {
+                    let len = data.len();
+                    (data, len)
+                }
+                None => (vec![], 0),
+            }));
+        }
+
         let mut mbuf = Vec::from(buf);
         mbuf.insert(0, 0);
         mbuf.insert(0, 0);
@@ -84,6 +184,15 @@ impl SafeReadWrite {
                     if x < 3 {
                         continue;
                     }
+                    if buf[2] == Ack as u8 || buf[2] == ResendRequest as u8 {
+                        // the partner is answering something we wrote (duplex use)
+                        self.handle_control(&buf[..3]);
+                        continue;
+                    }
+                    if buf[2] > End as u8 {
+                        // not ours, for example a late holepunch check
+                        continue;
+                    }
                     let id = u16::from_be_bytes([buf[0], buf[1]]);
                     if id <= self.packet_count_in as u16 {
                         self.socket
@@ -92,7 +201,7 @@ impl SafeReadWrite {
                     }
                     if id == self.packet_count_in as u16 {
                         if id == 0xffff {
-                            println!("\nPacket ID wrap successful.");
+                            status!("\nPacket ID wrap successful.");
                         }
                         try_again = false;
                         self.packet_count_in += 1;
@@ -100,8 +209,8 @@ impl SafeReadWrite {
                     } else if id > self.packet_count_in as u16
                         && (id - self.packet_count_in as u16) < 0xC000
                     {
-                        if !is_catching_up && !env::var("QFT_HIDE_DROPS").is_ok() {
-                            println!(
+                        if !is_catching_up && env::var("QFT_HIDE_DROPS").is_err() {
+                            status!(
                                 "\r\x1b[KA packet dropped: {} (got) is newer than {} (expected)",
                                 &id,
                                 &(self.packet_count_in as u16)
@@ -115,21 +224,78 @@ impl SafeReadWrite {
                             .expect("send error");
                     }
                     if buf[2] == End as u8 {
-                        return Ok((vec![], 0));
+                        return Ok(Some((vec![], 0)));
+                    }
+                }
+                Err(_) => {
+                    if !blocking {
+                        return Ok(None);
                     }
                 }
-                Err(_) => {}
             }
         }
         mbuf.remove(0);
         mbuf.remove(0);
         mbuf.remove(0);
         r.0 = mbuf;
-        return Ok(r);
+        Ok(Some(r))
+    }
+
+    /// Keeps a data packet which arrived while we were waiting for an Ack, so read_safe can return
+    /// it later.
+    fn queue_incoming(&mut self, packet: &[u8]) {
+        let id = u16::from_be_bytes([packet[0], packet[1]]);
+        if id <= self.packet_count_in as u16 {
+            let _ = self.socket.send(&[packet[0], packet[1], Ack as u8]);
+        }
+        if id == self.packet_count_in as u16 {
+            self.packet_count_in += 1;
+            self.received.push_back(if packet[2] == End as u8 {
+                None
+            } else {
+                Some(packet[3..].to_vec())
+            });
+        } else if id > self.packet_count_in as u16 && (id - self.packet_count_in as u16) < 0xC000 {
+            let id = (self.packet_count_in as u16).to_be_bytes();
+            let _ = self.socket.send(&[id[0], id[1], ResendRequest as u8]);
+        }
+    }
+
+    /// Handles an Ack or ResendRequest which arrived while we were reading.
+    fn handle_control(&mut self, packet: &[u8]) {
+        let mut n = u16::from_be_bytes([packet[0], packet[1]]);
+        let latest = (self.packet_count_out as u16).wrapping_sub(1);
+        if packet[2] == Ack as u8 {
+            self.last_transmitted.remove(&n);
+            if n == latest {
+                self.last_transmitted.clear();
+            }
+            return;
+        }
+        // ResendRequest: everything from n onwards that wasn't ACK'd yet
+        while let Some(buf) = self.last_transmitted.get(&n) {
+            let _ = self.socket.send(buf);
+            thread::sleep(Duration::from_millis(4));
+            if n == latest {
+                break;
+            }
+            n = n.wrapping_add(1);
+        }
+    }
+
+    /// Whether the partner hasn't acknowledged everything we wrote yet.
+    pub fn unacknowledged(&self) -> bool {
+        !self.last_transmitted.is_empty()
+    }
+
+    /// Tells the partner that we won't write anymore, while still allowing to read. This is the
+    /// duplex counterpart to end.
+    pub fn close_write(&mut self) {
+        let _ = self.internal_write_safe(&[], End, true, true, 3000);
     }
 
     pub fn end(mut self) -> UdpSocket {
-        let _ = self.internal_write_safe(&mut [], End, true, true, 3000);
+        self.close_write();
 
         self.socket
     }
@@ -174,7 +340,8 @@ impl SafeReadWrite {
             self.last_transmitted.insert(idn, vbuf);
             break;
         }
-        let mut buf = [0, 0, 0];
+        // taken out, so it can be used while self is borrowed
+        let mut buf = std::mem::take(&mut self.reply_buf);
         let mut wait = idn == 0xffff || flush;
         if self.last_transmitted.len() < 256 {
             self.socket
@@ -185,15 +352,16 @@ impl SafeReadWrite {
         }
         let mut start = unix_millis();
         if idn == 0xffff {
-            print!("\nPacket ID needs to wrap. Waiting for partner to catch up...")
+            print_status(
+                format_args!("\nPacket ID needs to wrap. Waiting for partner to catch up..."),
+                false,
+            )
         }
         let mut is_catching_up = false;
         loop {
             match (
                 if !wait {
                     self.socket.set_nonblocking(true).unwrap()
-                } else {
-                    ()
                 },
                 self.socket.recv(&mut buf).ok(),
                 self.socket.set_nonblocking(false).unwrap(),
@@ -201,6 +369,10 @@ impl SafeReadWrite {
                 .1
             {
                 Some(x) => {
+                    if x >= 3 && (buf[2] == Write as u8 || buf[2] == End as u8) {
+                        self.queue_incoming(&buf[..x]);
+                        continue;
+                    }
                     if x != 3 {
                         continue;
                     }
@@ -209,7 +381,7 @@ impl SafeReadWrite {
                         self.last_transmitted.remove(&n);
                         if n == idn {
                             if idn == 0xffff {
-                                println!("\r\x1b[KPacket ID wrap successful.");
+                                status!("\r\x1b[KPacket ID wrap successful.");
                             }
                             wait = false;
                             self.last_transmitted.clear(); // if the latest packet is ACK'd, all
@@ -219,9 +391,13 @@ impl SafeReadWrite {
                     if buf[2] == ResendRequest as u8 {
                         let mut n = u16::from_be_bytes([buf[0], buf[1]]);
                         thread::sleep(Duration::from_millis(100));
-                        while let Some(_) = self.socket.recv(&mut buf).ok() {}
-                        if !is_catching_up && !env::var("QFT_HIDE_DROPS").is_ok() {
-                            println!("\r\x1b[KA packet dropped: {}", &n);
+                        while let Ok(x) = self.socket.recv(&mut buf) {
+                            if x >= 3 && (buf[2] == Write as u8 || buf[2] == End as u8) {
+                                self.queue_incoming(&buf[..x]);
+                            }
+                        }
+                        if !is_catching_up && env::var("QFT_HIDE_DROPS").is_err() {
+                            status!("\r\x1b[KA packet dropped: {}", &n);
                         }
                         if !is_catching_up {
                             wait = true;
@@ -231,7 +407,7 @@ impl SafeReadWrite {
                                 if let Some(buf) = buf {
                                     loop {
                                         // resend until success
-                                        match self.socket.send(&buf.as_slice()) {
+                                        match self.socket.send(buf.as_slice()) {
                                             Ok(x) => {
                                                 if x != buf.len() {
                                                     continue;
@@ -258,7 +434,7 @@ impl SafeReadWrite {
                         break;
                     }
                     if unix_millis() - start > 10000 {
-                        println!("\n10s passed since last packet ==> Contact broke. Trying to resend packet...");
+                        status!("\n10s passed since last packet ==> Contact broke. Trying to resend packet...");
                         if let Some(buf) = self.last_transmitted.get(&idn) {
                             loop {
                                 match self.socket.send(buf) {
@@ -285,16 +461,35 @@ impl SafeReadWrite {
                 }
             }
         }
+        self.reply_buf = buf;
         self.socket
             .set_read_timeout(Some(Duration::from_millis(1000)))
             .unwrap();
-        return Ok(());
+        Ok(())
     }
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 0 {
+    let mut args: Vec<String> = std::env::args().collect();
+    if take_flag(&mut args, "--allow-relay") {
+        env::set_var("QFT_ALLOW_RELAY", "1");
+    }
+    if let Some(timeout) = take_option(&mut args, "--timeout") {
+        env::set_var("QFT_CONNECT_TIMEOUT", timeout);
+    }
+    // the helper's own --token lists the tokens it accepts
+    if args.get(1).is_some_and(|mode| mode != "helper") {
+        if let Some(token) = take_option(&mut args, "--token") {
+            env::set_var("QFT_HELPER_TOKEN", token);
+        }
+    }
+    // --listen and --connect take the place of the helper address
+    for name in ["--listen", "--connect"] {
+        if let Some(value) = take_option(&mut args, name) {
+            args.insert(2.min(args.len()), format!("{}={}", name, value));
+        }
+    }
+    if args.is_empty() {
         panic!("no args");
     }
     if args.len() == 1 {
@@ -306,111 +501,78 @@ fn main() {
         #[cfg(not(feature = "gui"))]
         print_args(&args)
     }
-    match args
-        .get(1)
-        .unwrap() // checked in previous if-statement
-        .as_str()
-    {
-        "helper" => helper(&args),
+    let mode = args.get(1).unwrap(); // checked in previous if-statement
+                                     // port mappings are removed on the way out, even after a panic
+    let unmap = portmap::Unmap;
+    let result = match mode.as_str() {
+        "helper-status" => helper::status(&args),
         "sender" => sender(&args, |_| {}),
         "receiver" => receiver(&args, |_| {}),
-        #[cfg(feature = "gui")]
-        "gui" => gui::gui().expect("can't use gui"),
-        #[cfg(not(feature = "gui"))]
-        "gui" => println!("Feature 'gui' was not enabled during compilation. GUI not available."),
-        "version" => println!("QFT version: {}", env!("CARGO_PKG_VERSION")),
-        _ => print_args(&args),
-    }
-}
-
-pub fn helper(args: &Vec<String>) {
-    let bind_addr = (
-        "0.0.0.0",
-        u16::from_str_radix(args[2].as_str(), 10).expect("invalid port: must be integer"),
-    );
-    let mut map: HashMap<[u8; 200], SocketAddr> = HashMap::new();
-    let listener = UdpSocket::bind(&bind_addr).expect("unable to create socket");
-    let mut buf = [0 as u8; 200];
-    let mut last_log_time = unix_millis();
-    let mut amount_since_log = 0;
-    let mut helper_log = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open("qft_helper_log.txt")
-        .expect("unable to create helper log");
-    loop {
-        let (l, addr) = listener.recv_from(&mut buf).expect("read error");
-        if l != 200 {
-            continue;
-        }
-        if map.contains_key(&buf) {
-            let other = map.get(&buf).unwrap();
-            // we got a connection
-            let mut bytes: &[u8] = addr.to_string().bytes().collect::<Vec<u8>>().leak();
-            let mut addr_buf = [0 as u8; 200];
-            for i in 0..bytes.len().min(200) {
-                addr_buf[i] = bytes[i];
-            }
-            bytes = other.to_string().bytes().collect::<Vec<u8>>().leak();
-            let mut other_buf = [0 as u8; 200];
-            for i in 0..bytes.len().min(200) {
-                other_buf[i] = bytes[i];
-            }
-            if listener.send_to(&addr_buf, other).is_ok()
-                && listener.send_to(&other_buf, addr).is_ok()
-            {
-                // success!
-                println!("Helped {} and {}! :D", addr, other);
-                amount_since_log += 1;
-                if unix_millis() - last_log_time > 10000 {
-                    let d = PrimitiveDateTime::new(
-                        Date::from_calendar_date(1970, time::Month::January, 1).unwrap(),
-                        Time::MIDNIGHT,
-                    ) + Duration::from_millis(unix_millis());
-                    helper_log
-                        .write(
-                            format!(
-                                "{} | {} {}>\n",
-                                d,
-                                amount_since_log,
-                                amount_since_log * Wrap("=")
-                            )
-                            .as_bytes(),
-                        )
-                        .expect("error writing to log");
-                    helper_log.flush().expect("error writing to log");
-                    last_log_time = unix_millis();
-                    amount_since_log = 0;
+        "pipe" => pipe::pipe(&args),
+        "tunnel" => tunnel::tunnel(&args),
+        mode => {
+            match mode {
+                "helper" => helper::helper(&args),
+                "helper-bench" => helper::bench(&args),
+                "stun" => stun::stun(&args),
+                "doctor" => doctor::doctor(&args),
+                #[cfg(feature = "gui")]
+                "gui" => gui::gui().expect("can't use gui"),
+                #[cfg(not(feature = "gui"))]
+                "gui" => {
+                    println!("Feature 'gui' was not enabled during compilation. GUI not available.")
                 }
+                "version" => println!("QFT version: {}", env!("CARGO_PKG_VERSION")),
+                _ => print_args(&args),
             }
-            map.remove(&buf);
-        } else {
-            map.insert(buf, addr);
+            Ok(())
         }
+    };
+    // exiting skips destructors
+    drop(unmap);
+    if let Err(error) = result {
+        error.exit();
     }
 }
 
-pub fn sender<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
-    let connection = holepunch(args);
+/// Binds to [::], which accepts IPv4 as well on most systems (as ::ffff:a.b.c.d). 0.0.0.0 is for
+/// systems without IPv6.
+fn bind_dual_stack(port: u16) -> Result<UdpSocket, Error> {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+        .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)))
+}
+
+/// Turns IPv4 addresses which arrived on an IPv6 socket (::ffff:a.b.c.d) back into plain IPv4.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+pub fn sender<F: Fn(f32)>(args: &[String], on_progress: F) -> Result<(), HolepunchError> {
+    let file_size = args.get(4).and_then(|path| fs::metadata(path).ok());
+    let connection = holepunch(args, Role::Sender, file_size.map(|m| m.len()))?;
     let dly = args
         .get(5)
-        .map(|s| u64::from_str_radix(s, 10))
+        .map(|s| s.parse::<u64>())
         .unwrap_or(Ok(500))
         .expect("bad delay operand");
     let br = args
         .get(6)
-        .map(|s| u32::from_str_radix(s, 10))
+        .map(|s| s.parse::<u32>())
         .unwrap_or(Ok(256))
         .expect("bad bitrate argument");
     let begin = args
         .get(7)
-        .map(|s| u64::from_str_radix(s, 10))
+        .map(|s| s.parse::<u64>())
         .unwrap_or(Ok(0))
         .expect("bad begin operand");
-    let mut buf: Vec<u8> = Vec::new();
-    buf.resize(br as usize, 0);
-    let mut buf = buf.leak();
+    let buf: Vec<u8> = vec![0; br as usize];
+    let buf = buf.leak();
     let mut file = File::open(args.get(4).unwrap_or_else(|| {
         print_args(args);
         panic!("unreachable")
@@ -432,12 +594,12 @@ pub fn sender<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
     println!("Length: {}", &len);
     let mut time = unix_millis();
     loop {
-        let read = file.read(&mut buf).expect("file read error");
-        if read == 0 && !env::var("QFT_STREAM").is_ok() {
+        let read = file.read(buf).expect("file read error");
+        if read == 0 && env::var("QFT_STREAM").is_err() {
             println!();
             println!("Transfer done. Thank you!");
             sc.end();
-            return;
+            return Ok(());
         }
 
         sc.write_safe(&buf[..read], dly).expect("send error");
@@ -461,26 +623,25 @@ pub fn sender<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
     }
 }
 
-pub fn receiver<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
-    let connection = holepunch(args);
+pub fn receiver<F: Fn(f32)>(args: &[String], on_progress: F) -> Result<(), HolepunchError> {
+    let connection = holepunch(args, Role::Receiver, None)?;
     let br = args
         .get(5)
-        .map(|s| u32::from_str_radix(s, 10))
+        .map(|s| s.parse::<u32>())
         .unwrap_or(Ok(256))
         .expect("bad bitrate argument");
     let begin = args
         .get(6)
-        .map(|s| u64::from_str_radix(s, 10))
+        .map(|s| s.parse::<u64>())
         .unwrap_or(Ok(0))
         .expect("bad begin operand");
-    let mut buf: Vec<u8> = Vec::new();
-    buf.resize(br as usize, 0);
+    let buf: Vec<u8> = vec![0; br as usize];
     let buf: &[u8] = buf.leak();
     let mut file = OpenOptions::new()
         .truncate(false)
         .write(true)
         .create(true)
-        .open(&args.get(4).unwrap_or_else(|| {
+        .open(args.get(4).unwrap_or_else(|| {
             print_args(args);
             panic!("unreachable")
         }))
@@ -495,9 +656,9 @@ pub fn receiver<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
     let mut sc = SafeReadWrite::new(connection);
     let mut bytes_received: u64 = 0;
     let mut last_update = unix_millis();
-    let mut len_bytes = [0 as u8; 8];
+    let len_bytes = [0u8; 8];
     let len = sc
-        .read_safe(&mut len_bytes)
+        .read_safe(&len_bytes)
         .expect("unable to read length from sender")
         .0;
     let len = u64::from_be_bytes([
@@ -512,10 +673,10 @@ pub fn receiver<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
         if amount == 0 {
             println!();
             println!("Transfer done. Thank you!");
-            return;
+            return Ok(());
         }
 
-        file.write(buf).expect("write error");
+        file.write_all(buf).expect("write error");
         file.flush().expect("file flush error");
         bytes_received += amount as u64;
         if (bytes_received % (br * 20) as u64) < (br as u64) {
@@ -537,15 +698,75 @@ pub fn receiver<F: Fn(f32)>(args: &Vec<String>, on_progress: F) {
     }
 }
 
-fn holepunch(args: &Vec<String>) -> UdpSocket {
-    let bind_addr = (Ipv4Addr::from(0 as u32), 0);
-    let holepunch = UdpSocket::bind(&bind_addr).expect("unable to create socket");
-    holepunch
-        .connect(args.get(2).unwrap_or_else(|| {
+/// Why connecting to the partner failed. Each stage has its own exit code, for scripts.
+pub enum HolepunchError {
+    HelperUnreachable(String),
+    PartnerNeverArrived,
+    PunchFailed,
+    HelperRefused(String),
+}
+
+impl fmt::Display for HolepunchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HolepunchError::HelperUnreachable(helper) => write!(
+                f,
+                "The helper {} didn't answer. Check its address and your internet connection, or \
+                 try another helper.",
+                helper
+            ),
+            HolepunchError::PartnerNeverArrived => write!(
+                f,
+                "Your partner didn't show up in time. Make sure you both use the same helper and \
+                 phrase, and that your partner started qft too (--timeout waits longer)."
+            ),
+            HolepunchError::PunchFailed => write!(
+                f,
+                "Your partner was found, but no connection could be made through your NATs. Run \
+                 `qft doctor <helper>` on both ends to find out why, or allow a relay with \
+                 --allow-relay."
+            ),
+            HolepunchError::HelperRefused(reason) => {
+                write!(f, "The helper refused to pair you: {}.", reason)
+            }
+        }
+    }
+}
+
+impl HolepunchError {
+    /// Prints the error and exits with its code. Only for main, the GUI prints it and stays open.
+    fn exit(self) -> ! {
+        eprintln!("{}", self);
+        process::exit(match self {
+            HolepunchError::HelperUnreachable(_) => 2,
+            HolepunchError::PartnerNeverArrived => 3,
+            HolepunchError::PunchFailed => 4,
+            HolepunchError::HelperRefused(_) => 5,
+        })
+    }
+}
+
+/// The overall connect timeout from --timeout or QFT_CONNECT_TIMEOUT (seconds, 0 waits forever).
+fn connect_timeout() -> Option<Duration> {
+    let seconds = env::var("QFT_CONNECT_TIMEOUT")
+        .map(|s| s.parse::<u64>().expect("invalid timeout: must be integer"))
+        .unwrap_or(300);
+    (seconds != 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Connects to the partner. `role` and `file_size` tell the helper what kind of partner we need.
+fn holepunch(
+    args: &[String],
+    role: Role,
+    file_size: Option<u64>,
+) -> Result<UdpSocket, HolepunchError> {
+    let mut helper = args
+        .get(2)
+        .unwrap_or_else(|| {
             print_args(args);
             panic!("unreachable")
-        }))
-        .expect("unable to connect to helper");
+        })
+        .clone();
     let bytes = args
         .get(3)
         .unwrap_or_else(|| {
@@ -553,26 +774,559 @@ fn holepunch(args: &Vec<String>) -> UdpSocket {
             panic!("unreachable")
         })
         .as_bytes();
-    let mut buf = [0 as u8; 200];
-    for i in 0..bytes.len().min(200) {
-        buf[i] = bytes[i];
+    // `-` as phrase gets a code from the helper
+    let wants_code = bytes == b"-";
+    if wants_code && (helper == "-" || helper.starts_with("--")) {
+        panic!("codes come from a helper, choose a phrase yourself to connect without one");
     }
-    holepunch.send(&buf).expect("unable to talk to helper");
-    holepunch
-        .recv(&mut buf)
-        .expect("unable to receive from helper");
-    // buf should now contain our partner's address data.
-    let mut s = Vec::from(buf);
-    s.retain(|e| *e != 0);
-    let bind_addr = String::from_utf8_lossy(s.as_slice()).to_string();
-    println!(
+    let timeout = connect_timeout();
+    let start = unix_millis();
+    let give_up = timeout.map_or(u64::MAX, |t| start + t.as_millis() as u64);
+    if let Some(port) = helper.strip_prefix("--listen=") {
+        return direct::listen(port, bytes, timeout).ok_or(HolepunchError::PartnerNeverArrived);
+    }
+    if let Some(target) = helper.strip_prefix("--connect=") {
+        return direct::connect(target, bytes, timeout).ok_or(HolepunchError::PartnerNeverArrived);
+    }
+    if helper == "-" {
+        // no helper: look in the local network, and only use a helper if that doesn't work
+        let fallback = env::var("QFT_FALLBACK_HELPER").ok();
+        let lan_timeout = match fallback {
+            Some(_) => Some(Duration::from_secs(5)),
+            None => timeout,
+        };
+        if let Some(socket) = lan::discover(bytes, lan_timeout) {
+            eprintln!("Connection successful.");
+            return Ok(socket);
+        }
+        let Some(fallback) = fallback else {
+            return Err(HolepunchError::PartnerNeverArrived);
+        };
+        helper = fallback;
+        eprintln!(
+            "Nobody found in the local network, asking {} instead.",
+            helper
+        );
+    }
+    let mut sockets = helper_sockets(&helper);
+    let code;
+    // sent along when registering, so the helper can give the number to someone else once we are
+    // paired
+    let mut nameplate = None;
+    let bytes = if wants_code {
+        let Some((socket, helper_addr)) = sockets.first() else {
+            return Err(HolepunchError::HelperUnreachable(helper));
+        };
+        code = match words::request(socket, *helper_addr) {
+            Ok((number, code)) => {
+                nameplate = Some(number);
+                code
+            }
+            Err(Some(reason)) => return Err(HolepunchError::HelperRefused(reason)),
+            Err(None) => {
+                eprintln!("The helper didn't hand out a code (older helpers can't).");
+                return Err(HolepunchError::HelperUnreachable(helper));
+            }
+        };
+        let example = match role {
+            Role::Sender => format!("qft receiver {} {} <filename>", helper, code),
+            Role::Receiver => format!("qft sender {} {} <filename>", helper, code),
+            _ => format!("the same command with {} as phrase", code),
+        };
+        eprintln!(
+            "Your code is {}. Your partner uses it as the phrase: {}",
+            code, example
+        );
+        code.as_bytes()
+    } else {
+        bytes
+    };
+    let mut buf = [0u8; 200];
+    let n = bytes.len().min(200);
+    buf[..n].copy_from_slice(&bytes[..n]);
+    // Register over every address family we can reach the helper with. The helper pairs each
+    // family separately, so we know which ones our partner has as well. Our candidates go first,
+    // so the helper has them when the partner arrives.
+    let tie_breaker = nonce();
+    // our candidate list for each address family, sent along until the partner arrives
+    let mut lists: HashMap<bool, Vec<u8>> = HashMap::new();
+    // The registration and the helper's answer can get lost, so registrations are repeated with
+    // growing pauses. They also keep our NAT's mapping alive. The helper tells us that it got them,
+    // which also shows how long the way to it takes.
+    // But helpers from before the candidates take every packet of 200 bytes or more for a phrase,
+    // cut down to that, and pair a repeated one with the first. So until the helper answered in
+    // protocol v2, only the probe is repeated, which is shorter. Helpers which don't answer it get
+    // the legacy registration instead: STUN requests, and the phrase once if those aren't answered
+    // either. Only helpers which know candidates answer them, and only they get the candidates and
+    // the phrase again and again.
+    let mut v2 = false;
+    let mut legacy = false;
+    let mut knows_candidates = false;
+    // how often the legacy registration went out
+    let mut legacy_rounds = 0;
+    let (stun_request, transaction) = stun::binding_request(false);
+    // when the last packet which the helper answers right away went out, per address family
+    let mut sent_at: HashMap<bool, u64> = HashMap::new();
+    // the helper's cookies per address family, if it wants us to prove our address
+    let mut cookies: HashMap<bool, Vec<u8>> = HashMap::new();
+    // what the helper may see as our address, a pairing with one of them is a pairing with ourselves
+    let mut own: Vec<SocketAddr> = vec![];
+    // private helpers only pair peers which know one of their tokens
+    let token = env::var("QFT_HELPER_TOKEN").ok();
+    let probe = |socket: &UdpSocket, helper_addr: SocketAddr| {
+        let mut message = protocol::Message::new(protocol::Kind::Register);
+        if let Some(token) = &token {
+            let credential = guard::credential(token.as_bytes(), b"register", &[]);
+            message = message.with(protocol::CREDENTIAL, &credential);
+        }
+        let message = message.encode_padded(protocol::PROBE_SIZE);
+        socket.send_to(&message, helper_addr).is_ok()
+    };
+    let register =
+        |socket: &UdpSocket, helper_addr: SocketAddr, list: &[u8], cookie: Option<&Vec<u8>>| {
+            let mut message = protocol::Message::new(protocol::Kind::Register)
+                .with(protocol::PHRASE, bytes)
+                .with(protocol::ROLE, &[role.id()])
+                .with(protocol::CANDIDATES, list);
+            if let Some(size) = file_size {
+                message = message.with(protocol::FILE_SIZE, &size.to_be_bytes());
+            }
+            if let Some(cookie) = cookie {
+                message = message.with(protocol::COOKIE, cookie);
+            }
+            if let Some(nameplate) = nameplate {
+                message = message.with(protocol::NAMEPLATE, &nameplate.to_be_bytes());
+            }
+            if let Some(token) = &token {
+                let credential = guard::credential(token.as_bytes(), b"register", bytes);
+                message = message.with(protocol::CREDENTIAL, &credential);
+            }
+            let message = message.encode_padded(protocol::REGISTER_SIZE);
+            let _ = socket.send_to(&message, helper_addr);
+        };
+    let register_legacy = |socket: &UdpSocket,
+                           helper_addr: SocketAddr,
+                           list: &[u8],
+                           cookie: Option<&Vec<u8>>,
+                           candidates: bool,
+                           phrase: bool| {
+        if let Some(cookie) = cookie {
+            let mut packet = Vec::from(guard::COOKIE_MAGIC);
+            packet.extend(cookie);
+            let _ = socket.send_to(&packet, helper_addr);
+        }
+        let _ = socket.send_to(&stun_request, helper_addr);
+        // the helper takes them when the phrase arrives
+        if candidates {
+            let mut message = Vec::from(CANDIDATES_MAGIC);
+            message.extend(list);
+            message.resize(message.len().max(CANDIDATES_SIZE), b'\n');
+            let _ = socket.send_to(&message, helper_addr);
+        }
+        if phrase {
+            let _ = socket.send_to(&buf, helper_addr);
+        }
+    };
+    let stun_server = env::var("QFT_STUN").ok().map(|s| stun::resolve(&s));
+    let predict_ports = env::var("QFT_PORT_PREDICTION").is_ok();
+    // how our NAT picks ports, per address family
+    let mut mappings: HashMap<bool, Mapping> = HashMap::new();
+    sockets.retain(|(socket, helper_addr)| {
+        let mut list = CandidateList {
+            tie_breaker,
+            candidates: ice::host_candidates(socket, *helper_addr),
+            mapping: None,
+            allow_relay: env::var("QFT_ALLOW_RELAY").is_ok(),
+            punch: true,
+        };
+        if predict_ports {
+            let server = stun_server.as_ref().and_then(|s| s.as_ref().ok()).copied();
+            list.mapping = predict::measure(socket, *helper_addr, server);
+            match list.mapping {
+                Some(Mapping::Sequential { delta, .. }) => eprintln!(
+                    "Your NAT is symmetric and counts ports ({:+}), the partner will guess them.",
+                    delta
+                ),
+                Some(Mapping::Random) => {
+                    eprintln!(
+                        "Your NAT is symmetric and picks random ports, that needs many tries."
+                    )
+                }
+                None => (),
+            }
+            if let Some(mapping) = list.mapping {
+                mappings.insert(helper_addr.is_ipv6(), mapping);
+            }
+            socket
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        if env::var("QFT_PORT_MAPPING").is_ok() {
+            if let Some(addr) = portmap::map(socket, *helper_addr) {
+                list.candidates.push(Candidate {
+                    kind: CandidateKind::Mapped,
+                    addr,
+                });
+            }
+        }
+        // our public address is what the helper gives the partner anyway, but a STUN server might
+        // see a different one
+        if let Some(Ok(server)) = &stun_server {
+            if server.is_ipv6() == helper_addr.is_ipv6() {
+                match stun::binding(socket, *server, Duration::from_secs(2)) {
+                    Ok(addr) => list.candidates.push(Candidate {
+                        kind: CandidateKind::ServerReflexive,
+                        addr,
+                    }),
+                    Err(e) => eprintln!("STUN server {} didn't help: {}", server, e),
+                }
+                socket
+                    .set_read_timeout(Some(Duration::from_millis(50)))
+                    .unwrap();
+            }
+        }
+        own.extend(list.candidates.iter().map(|c| c.addr));
+        own.extend(local_addr_towards(socket, *helper_addr));
+        let list = list.encode();
+        sent_at.insert(helper_addr.is_ipv6(), unix_millis());
+        // sending fails if there is no route, for example on machines without IPv6
+        if probe(socket, *helper_addr) {
+            lists.insert(helper_addr.is_ipv6(), list);
+            return true;
+        }
+        false
+    });
+    if sockets.is_empty() {
+        return Err(HolepunchError::HelperUnreachable(helper));
+    }
+    // when the helper first told us that it got the registration
+    let mut registered: Option<u64> = None;
+    // the seconds shown in the status line
+    let mut shown_wait = None;
+    // the round trip to the helper per address family, to tell how old its time in the reply is
+    let mut round_trips: HashMap<bool, u64> = HashMap::new();
+    let mut interval = 1000;
+    let mut next_send = unix_millis() + interval;
+    // IPv6 is preferred, because it usually doesn't need any NAT traversal. If the IPv4 answer
+    // comes first, give the IPv6 one some time to arrive too.
+    let mut chosen: Option<(usize, protocol::Paired, u64)> = None;
+    let mut deadline = u64::MAX;
+    while unix_millis() < deadline {
+        if unix_millis() > give_up && chosen.is_none() {
+            if registered.is_some() {
+                eprintln!();
+            }
+            match registered {
+                Some(_) => return Err(HolepunchError::PartnerNeverArrived),
+                None => return Err(HolepunchError::HelperUnreachable(helper)),
+            }
+        }
+        if let (Some(since), None) = (registered, &chosen) {
+            let waited = (unix_millis() - since) / 1000;
+            if shown_wait != Some(waited) {
+                shown_wait = Some(waited);
+                eprint!(
+                    "\r\x1b[KRegistered with the helper, waiting for the partner ({}s)...",
+                    waited
+                );
+            }
+        }
+        if unix_millis() >= next_send && chosen.is_none() {
+            // private helpers ignore the legacy protocol, which can't carry the token
+            if !v2 && !legacy && token.is_none() && interval == 2000 {
+                eprintln!("The helper doesn't answer protocol v2, trying the legacy one...");
+                if bytes.len() > 200 {
+                    eprintln!(
+                        "Warning: The legacy protocol only uses the first 200 bytes of the phrase."
+                    );
+                }
+                legacy = true;
+            }
+            if registered.is_none() && interval == 4000 {
+                if token.is_some() {
+                    eprintln!(
+                        "The helper hasn't answered yet (private helpers ignore registrations \
+                         with a wrong token)."
+                    );
+                } else {
+                    eprintln!(
+                        "The helper hasn't answered yet (older helpers only answer once the \
+                         partner is there)."
+                    );
+                }
+            }
+            for (socket, helper_addr) in &sockets {
+                let family = helper_addr.is_ipv6();
+                if registered.is_none() {
+                    sent_at.insert(family, unix_millis());
+                }
+                let (list, cookie) = (&lists[&family], cookies.get(&family));
+                if v2 {
+                    register(socket, *helper_addr, list, cookie);
+                } else if legacy {
+                    let phrase = knows_candidates || legacy_rounds == 1;
+                    register_legacy(socket, *helper_addr, list, cookie, knows_candidates, phrase);
+                } else {
+                    probe(socket, *helper_addr);
+                }
+            }
+            if legacy {
+                legacy_rounds += 1;
+            }
+            interval = (interval * 2).min(15000);
+            next_send = unix_millis() + interval;
+        }
+        for (i, (socket, helper_addr)) in sockets.iter().enumerate() {
+            let mut reply = [0u8; 2048];
+            let Ok((l, from)) = socket.recv_from(&mut reply) else {
+                continue;
+            };
+            if canonical_addr(from) != canonical_addr(*helper_addr) {
+                continue;
+            }
+            let family = helper_addr.is_ipv6();
+            let paired = if let Some(message) = protocol::Message::decode(&reply[..l]) {
+                // Registering in v2 is safe now. Once we registered the legacy way, we stay with
+                // that, because the partner might already be paired with that registration.
+                if !v2 && !legacy {
+                    v2 = true;
+                    next_send = 0;
+                }
+                knows_candidates = true;
+                match message.kind {
+                    protocol::Kind::Paired => protocol::Paired::decode(&message),
+                    _ if chosen.is_some() => None,
+                    protocol::Kind::Waiting | protocol::Kind::Keepalive => {
+                        round_trips
+                            .entry(family)
+                            .or_insert(unix_millis() - sent_at[&family]);
+                        registered.get_or_insert(unix_millis());
+                        None
+                    }
+                    protocol::Kind::Error => match message.code() {
+                        Some(protocol::EXPIRED) => {
+                            eprintln!(
+                                "\r\x1b[KThe helper forgot about you (expired), registering \
+                                 again..."
+                            );
+                            next_send = 0;
+                            None
+                        }
+                        Some(protocol::COOKIE_REQUIRED) => {
+                            // sending it back proves that we really are at this address
+                            if let Some(cookie) = message.get(protocol::COOKIE) {
+                                cookies.insert(family, cookie.to_vec());
+                                next_send = 0;
+                            }
+                            None
+                        }
+                        // the answer to the probe
+                        Some(protocol::BAD_REQUEST) if bytes.len() <= protocol::MAX_PHRASE => None,
+                        Some(protocol::UNSUPPORTED_VERSION) if !legacy => {
+                            legacy = true;
+                            next_send = 0;
+                            None
+                        }
+                        _ => {
+                            let text = message.get(protocol::MESSAGE).unwrap_or(b"no reason");
+                            return Err(HolepunchError::HelperRefused(
+                                String::from_utf8_lossy(text).to_string(),
+                            ));
+                        }
+                    },
+                    protocol::Kind::Register
+                    | protocol::Kind::Status
+                    | protocol::Kind::Nameplate => None,
+                }
+            } else if let Some(response) = stun::parse_response(&reply[..l], &transaction) {
+                if let Ok((addr, _)) = response {
+                    own.push(canonical_addr(addr));
+                }
+                // the candidates have to be there before the phrase
+                if legacy && !knows_candidates {
+                    next_send = 0;
+                }
+                knows_candidates = true;
+                round_trips
+                    .entry(family)
+                    .or_insert(unix_millis() - sent_at[&family]);
+                registered.get_or_insert(unix_millis());
+                None
+            } else if reply.starts_with(guard::COOKIE_MAGIC) && chosen.is_none() {
+                // sending it back proves that we really are at this address
+                cookies.insert(family, reply[guard::COOKIE_MAGIC.len()..l].to_vec());
+                knows_candidates = true;
+                next_send = 0;
+                None
+            } else if &reply[..l] == pairing::EXPIRED && chosen.is_none() {
+                eprintln!("\r\x1b[KThe helper forgot about you (expired), registering again...");
+                knows_candidates = true;
+                next_send = 0;
+                None
+            } else {
+                protocol::Paired::decode_legacy(&reply[..l])
+            };
+            let Some(paired) = paired else {
+                continue;
+            };
+            if own.contains(&canonical_addr(paired.partner)) {
+                eprintln!("\r\x1b[KThe helper paired you with yourself, registering again...");
+                legacy_rounds = legacy_rounds.min(1);
+                next_send = 0;
+                continue;
+            }
+            if family || sockets.len() == 1 {
+                chosen = Some((i, paired, unix_millis()));
+                deadline = 0;
+                break;
+            }
+            if chosen.is_none() {
+                chosen = Some((i, paired, unix_millis()));
+                deadline = unix_millis() + 500;
+            }
+        }
+    }
+    if registered.is_some() {
+        eprintln!();
+    }
+    let (i, paired, received) = chosen.unwrap();
+    let (holepunch, helper_addr) = sockets.swap_remove(i);
+    // the partner's address data, and its candidates if it sent any
+    let partner = paired.partner;
+    status!(
         "Holepunching {} (partner) and :{} (you).",
-        bind_addr,
+        partner,
         holepunch.local_addr().unwrap().port()
     );
+    if let Some(size) = paired.file_size {
+        eprintln!("Your partner is going to send {} bytes.", size);
+    }
+    if let Some(mut remote) = CandidateList::decode(&paired.candidates) {
+        if !remote.candidates.iter().any(|c| c.addr == partner) {
+            remote.candidates.push(Candidate {
+                kind: CandidateKind::ServerReflexive,
+                addr: partner,
+            });
+        }
+        // the partner's NAT will give us a new port, which the helper never saw
+        if let Some(mapping) = remote.mapping {
+            remote
+                .candidates
+                .extend(predict::predicted_candidates(mapping, partner.ip()));
+        }
+        eprintln!(
+            "Checking {} candidate addresses of the partner...",
+            remote.candidates.len()
+        );
+        let tag = ice::phrase_tag(bytes);
+        if let Some(addr) = ice::connect(
+            &holepunch,
+            tag,
+            tie_breaker,
+            &remote,
+            Duration::from_secs(10),
+        ) {
+            holepunch
+                .connect(addr)
+                .map_err(|_| HolepunchError::PunchFailed)?;
+            holepunch
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            holepunch
+                .set_write_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            status!("Holepunch and connection successful.");
+            return Ok(holepunch);
+        }
+        let local_mapping = mappings.get(&helper_addr.is_ipv6()).copied();
+        match (local_mapping, remote.mapping) {
+            (Some(Mapping::Random), Some(Mapping::Random)) => eprintln!(
+                "You and your partner both have NATs with random ports, a direct connection is very \
+                 unlikely to work."
+            ),
+            (Some(Mapping::Random), _) | (_, Some(Mapping::Random)) => {
+                eprintln!("Guessing the ports of the symmetric NAT...");
+                let many_sockets = local_mapping == Some(Mapping::Random);
+                if let Some(socket) =
+                    predict::birthday(&holepunch, partner, tag, many_sockets, Duration::from_secs(15))
+                {
+                    socket
+                        .set_read_timeout(Some(Duration::from_secs(1)))
+                        .unwrap();
+                    socket
+                        .set_write_timeout(Some(Duration::from_secs(1)))
+                        .unwrap();
+                    status!("Holepunch and connection successful.");
+                    return Ok(socket);
+                }
+            }
+            _ => (),
+        }
+        if remote.punch {
+            eprintln!("No candidate worked, trying regular holepunching.");
+            let round_trip = round_trips.get(&helper_addr.is_ipv6()).copied();
+            let schedule = match paired.schedule {
+                Some((time, punch_at)) => {
+                    punch::Schedule::new(time, punch_at, round_trip, received)
+                }
+                None => punch::Schedule {
+                    offset: punch::clock_offset(&holepunch, helper_addr).unwrap_or_else(|| {
+                        eprintln!(
+                            "The helper doesn't tell the time, hoping that the clocks agree."
+                        );
+                        0
+                    }),
+                    punch_at: 0,
+                },
+            };
+            if punch::punch(&holepunch, partner, tag, &schedule, Duration::from_secs(15)) {
+                holepunch
+                    .connect(partner)
+                    .map_err(|_| HolepunchError::PunchFailed)?;
+                holepunch
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+                holepunch
+                    .set_write_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+                status!("Holepunch and connection successful.");
+                return Ok(holepunch);
+            }
+        }
+        // the last resort, if both of us agree to it
+        if remote.allow_relay && env::var("QFT_ALLOW_RELAY").is_ok() {
+            eprintln!("Asking the helper to relay...");
+            match relay::request(&holepunch, helper_addr, Duration::from_secs(10)) {
+                Ok(()) => {
+                    holepunch
+                        .connect(helper_addr)
+                        .map_err(|_| HolepunchError::PunchFailed)?;
+                    holepunch
+                        .set_read_timeout(Some(Duration::from_secs(1)))
+                        .unwrap();
+                    holepunch
+                        .set_write_timeout(Some(Duration::from_secs(1)))
+                        .unwrap();
+                    eprintln!(
+                        "Connected through the helper's relay. The helper can see your data."
+                    );
+                    return Ok(holepunch);
+                }
+                Err(e) => eprintln!("No relay: {}.", e),
+            }
+        } else if env::var("QFT_ALLOW_RELAY").is_ok() {
+            eprintln!("Your partner didn't allow a relay (--allow-relay).");
+        }
+        if remote.punch {
+            return Err(HolepunchError::PunchFailed);
+        }
+        eprintln!("No candidate worked, trying regular holepunching.");
+    }
+    // The partner is an older version, which only knows these. They can't tell each other apart,
+    // so both ends have to pick the same one.
     holepunch
-        .connect(SocketAddrV4::from_str(bind_addr.as_str()).unwrap())
-        .expect("connection failed");
+        .connect(partner)
+        .map_err(|_| HolepunchError::PunchFailed)?;
     holepunch
         .set_read_timeout(Some(Duration::from_secs(1)))
         .unwrap();
@@ -580,18 +1334,21 @@ fn holepunch(args: &Vec<String>) -> UdpSocket {
         .set_write_timeout(Some(Duration::from_secs(1)))
         .unwrap();
     if env::var("QFT_USE_TIMED_HOLEPUNCH").is_ok() {
-        println!("Warning: You are using the QFT_USE_TIMED_HOLEPUNCH environment variable. This won't allow for more \
-            backwards-compatibility, rather it only exists as a fallback for bad connections. Please make absolutely \
-            sure your partner uses QFT_USE_TIMED_HOLEPUNCH as well, data might otherwise get corrupted on the receiver.");
-        println!("Waiting...");
+        status!("Warning: Your partner uses an older version of qft and you are using the QFT_USE_TIMED_HOLEPUNCH \
+            environment variable. Please make absolutely sure your partner uses QFT_USE_TIMED_HOLEPUNCH as well, data \
+            might otherwise get corrupted on the receiver. Newer versions pick the right holepunch on their own.");
+        status!("Waiting...");
         let mut stop = false;
         while !stop {
+            if unix_millis() > give_up {
+                return Err(HolepunchError::PunchFailed);
+            }
             thread::sleep(Duration::from_millis(500 - (unix_millis() % 500)));
-            println!("CONNECT {}", unix_millis());
+            status!("CONNECT {}", unix_millis());
             let _ = holepunch.send(&[0]);
             let result = holepunch.recv(&mut [0, 0]);
             if result.is_ok() && result.unwrap() == 1 {
-                holepunch.send(&[0, 0]).expect("connection failed");
+                let _ = holepunch.send(&[0, 0]);
                 let result = holepunch.recv(&mut [0, 0]);
                 if result.is_ok() && result.unwrap() == 2 {
                     stop = true;
@@ -599,49 +1356,289 @@ fn holepunch(args: &Vec<String>) -> UdpSocket {
             }
         }
     } else {
-        println!("Connecting...");
+        status!("Connecting...");
         thread::sleep(Duration::from_millis(500 - (unix_millis() % 500)));
         for _ in 0..40 {
             let m = unix_millis();
             let _ = holepunch.send(&[0]);
-            thread::sleep(Duration::from_millis((50 - (unix_millis() - m)).max(0)));
+            thread::sleep(Duration::from_millis(
+                50u64.saturating_sub(unix_millis() - m),
+            ));
         }
+        // without a single packet from the partner, nothing got through
+        let mut heard = false;
         let mut result = Ok(1);
         while result.is_ok() && result.unwrap() == 1 {
             result = holepunch.recv(&mut [0, 0]);
+            heard |= result.is_ok();
         }
-        holepunch.send(&[0, 0]).expect("connection failed");
-        holepunch.send(&[0, 0]).expect("connection failed");
+        let _ = holepunch.send(&[0, 0]);
+        let _ = holepunch.send(&[0, 0]);
         result = Ok(1);
         while result.is_ok() && result.unwrap() != 2 {
             result = holepunch.recv(&mut [0, 0]);
+            heard |= result.is_ok();
         }
         result = Ok(1);
         while result.is_ok() && result.unwrap() == 2 {
             result = holepunch.recv(&mut [0, 0]);
         }
+        if !heard {
+            return Err(HolepunchError::PunchFailed);
+        }
+    }
+    status!("Holepunch and connection successful.");
+    Ok(holepunch)
+}
+
+/// Our address as the helper sees it if there is no NAT in between: the one of the interface which
+/// leads to it, and the socket's port.
+fn local_addr_towards(socket: &UdpSocket, helper: SocketAddr) -> Option<SocketAddr> {
+    let bind_addr: SocketAddr = if helper.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let probe = UdpSocket::bind(bind_addr).ok()?;
+    probe.connect(helper).ok()?;
+    let ip = canonical_addr(probe.local_addr().ok()?).ip();
+    Some(SocketAddr::new(ip, socket.local_addr().ok()?.port()))
+}
+
+/// Creates a socket for each address family the helper can be reached with. The sockets time out
+/// quickly, so they can be polled one after another. Empty if the helper can't be resolved.
+fn helper_sockets(helper: &str) -> Vec<(UdpSocket, SocketAddr)> {
+    let addrs: Vec<SocketAddr> = match helper.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => vec![],
+    };
+    let mut sockets = vec![];
+    for is_ipv6 in [true, false] {
+        let Some(addr) = addrs.iter().find(|a| a.is_ipv6() == is_ipv6) else {
+            continue;
+        };
+        let bind_addr: SocketAddr = if is_ipv6 {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let Ok(socket) = UdpSocket::bind(bind_addr) else {
+            continue;
+        };
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        sockets.push((socket, *addr));
+    }
+    sockets
+}
+
+/// Removes `--name` from the arguments and returns whether it was there. Flags can go anywhere,
+/// the positional arguments around them keep their places.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+/// Removes `--name <value>` (or `--name=<value>`) from the arguments and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args
+        .iter()
+        .position(|arg| arg == name || arg.starts_with(&format!("{}=", name)))?;
+    let arg = args.remove(i);
+    match arg.split_once('=') {
+        Some((_, value)) => Some(value.to_owned()),
+        None if i < args.len() => Some(args.remove(i)),
+        None => panic!("{} needs a value", name),
     }
-    println!("Holepunch and connection successful.");
-    return holepunch;
 }
 
-fn print_args(args: &Vec<String>) {
-    let f = args.get(0).unwrap();
+fn print_args(args: &[String]) {
+    let f = args.first().unwrap();
     println!(
-        "No arguments. Needed: \n\
-         | {} helper <bind-port>\n\
+        "No arguments. Needed (use - as helper to search the local network instead, - as phrase to get \
+         a code from the helper, or --listen <port> \
+         on one end and --connect <host>:<port> on the other to connect directly; add --allow-relay \
+         to let the helper relay if nothing else works, --timeout <seconds> to wait longer or \
+         shorter for the partner, or --token <token> for a private helper): \n\
+         | {} helper <bind-port> [diagnostics-port] [--relay] [--relay-rate <KiB/s>] [--relay-quota <MiB>]\n\
+         |   [--waiting-ttl <seconds>] [--max-waiting <count>] [--max-waiting-per-ip <count>]\n\
+         |   [--rate-limit <packets/s>] [--cookie] [--threads <count>] [--metrics <local-port>]\n\
+         |   [--config <file>] [--bind <address>]... [--log <file>|-] [--log-interval <seconds>] [--no-stun]\n\
+         |   [--token <token>]...\n\
+         | {} helper-bench <helper-address>:<helper-port> [pairings] [threads]\n\
+         | {} helper-status <helper-address>:<helper-port>\n\
          | {} sender <helper-address>:<helper-port> <phrase> <filename> [send-dly] [bitrate] [skip]\n\
          | {} receiver <helper-address>:<helper-port> <phrase> <filename> [bitrate] [skip]\n\
+         | {} pipe <helper-address>:<helper-port> <phrase> [send-dly] [bitrate]\n\
+         | {} tunnel <helper-address>:<helper-port> <phrase> listen <local-port> [send-dly] [bitrate]\n\
+         | {} tunnel <helper-address>:<helper-port> <phrase> connect <target-host>:<target-port> [send-dly] [bitrate]\n\
+         | {} stun <stun-server>[:<port>]\n\
+         | {} doctor <helper-address>:<helper-port> [stun-server]\n\
          | {} gui\n\
          | {} version\n",
-        f, f, f, f, f
+        f, f, f, f, f, f, f, f, f, f, f, f
     );
     panic!("No arguments");
 }
 
+/// A number that is very unlikely to be picked by anyone else, for tie breaking. Not suitable for
+/// anything security related.
+pub fn nonce() -> u64 {
+    (SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64)
+        ^ ((process::id() as u64) << 32)
+}
+
+/// Fills `bytes` from the OS's random source, for anything that has to be unguessable. Panics if
+/// there is none, guessable secrets are worse than none.
+#[cfg(unix)]
+pub fn os_random(bytes: &mut [u8]) {
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(bytes))
+        .expect("unable to read from /dev/urandom");
+}
+
+/// Fills `bytes` from the OS's random source, for anything that has to be unguessable. std keys
+/// its hashers with it (and panics if it can't), so hashes with a fresh hasher are as good as the
+/// source itself.
+#[cfg(not(unix))]
+pub fn os_random(bytes: &mut [u8]) {
+    use std::{collections::hash_map::RandomState, hash::BuildHasher};
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let random = RandomState::new().hash_one(i).to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+}
+
 pub fn unix_millis() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_millis() as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A helper like the first ones: every packet is a phrase, cut down to 200 bytes, and the
+    /// second one with a phrase is paired with the first.
+    fn legacy_helper() -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut waiting: HashMap<[u8; 200], SocketAddr> = HashMap::new();
+            let mut buf = [0u8; 200];
+            while let Ok((l, from)) = socket.recv_from(&mut buf) {
+                if l != 200 {
+                    continue;
+                }
+                let Some(other) = waiting.remove(&buf) else {
+                    waiting.insert(buf, from);
+                    continue;
+                };
+                for (to, about) in [(other, from), (from, other)] {
+                    let mut reply = about.to_string().into_bytes();
+                    reply.resize(200, 0);
+                    let _ = socket.send_to(&reply, to);
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn pairs_through_legacy_helper() {
+        let helper = legacy_helper().to_string();
+        let peers = [Role::Sender, Role::Receiver].map(|role| {
+            let args = ["qft", "sender", &helper, "legacy test phrase"].map(String::from);
+            thread::spawn(move || holepunch(&args, role, None).ok())
+        });
+        let [sender, receiver] = peers.map(|peer| peer.join().unwrap().expect("not connected"));
+        // each end is connected to the other, not to itself
+        let sender_port = sender.local_addr().unwrap().port();
+        let receiver_port = receiver.local_addr().unwrap().port();
+        assert_eq!(sender.peer_addr().unwrap().port(), receiver_port);
+        assert_eq!(receiver.peer_addr().unwrap().port(), sender_port);
+        sender.send(b"hello").unwrap();
+        let mut buf = [0u8; 16];
+        let mut got = receiver.recv(&mut buf).unwrap();
+        // the last holepunch packets may still be on the way
+        while got < 5 {
+            got = receiver.recv(&mut buf).unwrap();
+        }
+        assert_eq!(&buf[..got], b"hello");
+    }
+
+    #[test]
+    fn duplex_resends_and_queues() {
+        let ours = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let partner = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        ours.connect(partner.local_addr().unwrap()).unwrap();
+        partner.connect(ours.local_addr().unwrap()).unwrap();
+        partner
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut sc = SafeReadWrite::new(ours);
+        let mut buf = [0u8; 64];
+
+        // the partner writes while we do, its packet waits for our next read
+        partner.send(&[0, 0, Write as u8, b'h', b'i']).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        for data in [b"one", b"two"] {
+            sc.write_safe(data, 0).unwrap();
+        }
+        let mut got = vec![];
+        while got.len() < 3 {
+            let l = partner.recv(&mut buf).unwrap();
+            got.push(buf[..l].to_vec());
+        }
+        assert!(got.contains(&vec![0, 0, Ack as u8]));
+        assert!(got.contains(&vec![0, 0, Write as u8, b'o', b'n', b'e']));
+        assert!(got.contains(&vec![0, 1, Write as u8, b't', b'w', b'o']));
+        let (data, l) = sc
+            .try_read_safe(&buf, Duration::from_millis(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(&data[..l], b"hi");
+
+        // nothing was acknowledged, so both are sent again after a second
+        let start = unix_millis();
+        while unix_millis() - start < 1500 {
+            assert!(sc
+                .try_read_safe(&buf, Duration::from_millis(10))
+                .unwrap()
+                .is_none());
+        }
+        for id in [0, 1] {
+            let l = partner.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..3], &[0, id, Write as u8]);
+            assert!(l > 3);
+        }
+
+        // a ResendRequest sends everything from there on
+        partner.send(&[0, 1, ResendRequest as u8]).unwrap();
+        assert!(sc
+            .try_read_safe(&buf, Duration::from_millis(100))
+            .unwrap()
+            .is_none());
+        let l = partner.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..l], &[0, 1, Write as u8, b't', b'w', b'o']);
+
+        // the Ack of the latest packet covers the ones before, then End closes the partner's side
+        partner.send(&[0, 1, Ack as u8]).unwrap();
+        partner.send(&[0, 1, End as u8]).unwrap();
+        let (_, l) = sc
+            .try_read_safe(&buf, Duration::from_millis(100))
+            .unwrap()
+            .unwrap();
+        assert_eq!(l, 0);
+        assert!(!sc.unacknowledged());
+        let l = partner.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..l], &[0, 1, Ack as u8]);
+    }
+}
@@ -0,0 +1,135 @@
+// Counters of what the helper did, in the Prometheus text format. They are served over HTTP on a
+// local port (--metrics) and through `qft helper-status`.
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::unix_millis;
+
+pub struct Metrics {
+    start: u64,
+    pub pairings: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub legacy_registrations: AtomicU64,
+    pub v2_registrations: AtomicU64,
+    /// Registrations with a version this helper doesn't speak.
+    pub other_registrations: AtomicU64,
+    /// Requests to a private helper without a valid credential.
+    pub unauthenticated: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            start: unix_millis(),
+            pairings: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            legacy_registrations: AtomicU64::new(0),
+            v2_registrations: AtomicU64::new(0),
+            other_registrations: AtomicU64::new(0),
+            unauthenticated: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters, and `waiting` and `expired` which the pairings keep themselves.
+    pub fn render(&self, waiting: u64, expired: u64) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+            text += &format!("# HELP qft_helper_{} {}\n", name, help);
+            text += &format!("# TYPE qft_helper_{} {}\n", name, kind);
+            for (labels, value) in values {
+                text += &format!("qft_helper_{}{} {}\n", name, labels, value);
+            }
+        };
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the helper started.",
+            &[("", (unix_millis() - self.start) / 1000)],
+        );
+        metric(
+            "pairings_total",
+            "counter",
+            "Pairs of peers which were told about each other.",
+            &[("", get(&self.pairings))],
+        );
+        metric(
+            "waiting",
+            "gauge",
+            "Peers waiting for their partner.",
+            &[("", waiting)],
+        );
+        metric(
+            "expired_total",
+            "counter",
+            "Waiting peers which were forgotten, because they waited too long or to make room.",
+            &[("", expired)],
+        );
+        metric(
+            "rate_limited_total",
+            "counter",
+            "Packets dropped because their IP sent too many.",
+            &[("", get(&self.rate_limited))],
+        );
+        metric(
+            "registrations_total",
+            "counter",
+            "Registration packets by protocol version.",
+            &[
+                ("{protocol=\"legacy\"}", get(&self.legacy_registrations)),
+                ("{protocol=\"v2\"}", get(&self.v2_registrations)),
+                ("{protocol=\"unsupported\"}", get(&self.other_registrations)),
+            ],
+        );
+        metric(
+            "unauthenticated_total",
+            "counter",
+            "Requests to a private helper which were dropped for lack of a valid token.",
+            &[("", get(&self.unauthenticated))],
+        );
+        text
+    }
+}
+
+/// Serves `render()` to whoever connects to `port` on this machine, in a thread.
+pub fn serve(port: u16, render: impl Fn() -> String + Send + Sync + 'static) {
+    let listener =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, port)).expect("unable to create metrics socket");
+    let render = Arc::new(render);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let render = render.clone();
+            // a slow client doesn't hold up the next one
+            thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+                // the request doesn't matter, every path gets the metrics
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+                let body = render();
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                         Content-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                );
+            });
+        }
+    });
+}
@@ -0,0 +1,315 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::{nonce, SafeReadWrite};
+
+// Every SafeReadWrite packet of a multiplexed session carries exactly one frame:
+// [kind: u8][stream id: u32 BE][payload...]
+const HELLO: u8 = 0;
+const OPEN: u8 = 1;
+const DATA: u8 = 2;
+const CLOSE: u8 = 3;
+const WINDOW: u8 = 4;
+
+/// How many bytes may be in flight on a stream before the reader has to hand out more credit.
+pub const INITIAL_WINDOW: usize = 256 * 1024;
+
+pub enum MuxEvent {
+    /// The partner opened a new stream.
+    Opened(u32),
+    Data(u32, Vec<u8>),
+    /// The partner won't send anything more on this stream.
+    Closed(u32),
+    /// The partner ended the whole session.
+    End,
+}
+
+struct Stream {
+    // bytes we may still send before the partner grants more
+    send_window: usize,
+    // bytes the local consumer processed which the partner doesn't know about yet
+    consumed: usize,
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+impl Stream {
+    fn new() -> Stream {
+        Stream {
+            send_window: INITIAL_WINDOW,
+            consumed: 0,
+            local_closed: false,
+            remote_closed: false,
+        }
+    }
+}
+
+/// Carries many independent, ordered streams over one SafeReadWrite connection. Each stream has
+/// its own flow control window, so a stream whose consumer is slow only stops itself, instead of
+/// blocking the other streams behind it. Losses are still repaired by SafeReadWrite for the
+/// connection as a whole.
+pub struct Mux {
+    sc: SafeReadWrite,
+    delay: u64,
+    max_payload: usize,
+    streams: HashMap<u32, Stream>,
+    next_id: u32,
+}
+
+impl Mux {
+    /// Both partners must call this at the same time, it exchanges a HELLO frame to decide who
+    /// uses even and who uses odd stream ids. `frame_size` is the maximum SafeReadWrite packet
+    /// size to use, and must match on both ends.
+    pub fn new(mut sc: SafeReadWrite, frame_size: usize, delay: u64) -> Mux {
+        if frame_size <= 5 {
+            panic!("frame size too small for mux frames");
+        }
+        let nonce = nonce();
+        let mut hello = vec![HELLO, 0, 0, 0, 0];
+        hello.extend_from_slice(&nonce.to_be_bytes());
+        sc.write_flush_safe(&hello, true, delay)
+            .expect("unable to send mux hello");
+        let buf = vec![0u8; frame_size];
+        let (mbuf, amount) = sc.read_safe(&buf).expect("unable to read mux hello");
+        if amount != 13 || mbuf[0] != HELLO {
+            panic!("partner doesn't speak the same stream protocol");
+        }
+        let partner_nonce = u64::from_be_bytes(mbuf[5..13].try_into().unwrap());
+        if partner_nonce == nonce {
+            panic!("partner sent our own mux hello back");
+        }
+        Mux {
+            sc,
+            delay,
+            max_payload: frame_size - 5,
+            streams: HashMap::new(),
+            next_id: if nonce > partner_nonce { 0 } else { 1 },
+        }
+    }
+
+    /// Opens a new stream and returns its id.
+    pub fn open(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(2);
+        self.streams.insert(id, Stream::new());
+        self.write_frame(OPEN, id, &[]);
+        id
+    }
+
+    /// How many bytes can be written to the stream right now.
+    pub fn send_window(&self, id: u32) -> usize {
+        match self.streams.get(&id) {
+            Some(s) if !s.local_closed => s.send_window,
+            _ => 0,
+        }
+    }
+
+    /// The largest payload a single frame can carry. Writing in chunks of this size avoids
+    /// splitting.
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Writes data to a stream. Callers are expected to check send_window first, data beyond the
+    /// window is still sent, but the partner may have to buffer it.
+    pub fn write(&mut self, id: u32, data: &[u8]) {
+        if let Some(s) = self.streams.get_mut(&id) {
+            s.send_window = s.send_window.saturating_sub(data.len());
+        }
+        for chunk in data.chunks(self.max_payload) {
+            self.write_frame(DATA, id, chunk);
+        }
+    }
+
+    /// Tells the partner that we won't write to this stream anymore. Reading is still possible
+    /// until the partner closes its side too.
+    pub fn close(&mut self, id: u32) {
+        let Some(s) = self.streams.get_mut(&id) else {
+            return;
+        };
+        if s.local_closed {
+            return;
+        }
+        s.local_closed = true;
+        if s.remote_closed {
+            self.streams.remove(&id);
+        }
+        self.write_frame(CLOSE, id, &[]);
+    }
+
+    /// Must be called when data received on a stream was processed, so the partner may send more.
+    pub fn consumed(&mut self, id: u32, amount: usize) {
+        let Some(s) = self.streams.get_mut(&id) else {
+            return;
+        };
+        s.consumed += amount;
+        if s.consumed >= INITIAL_WINDOW / 4 {
+            let amount = s.consumed as u32;
+            s.consumed = 0;
+            self.write_frame(WINDOW, id, &amount.to_be_bytes());
+        }
+    }
+
+    /// Waits up to `timeout` for the next frame. Flow control frames are handled here and give
+    /// None, just like a timeout does.
+    pub fn poll(&mut self, timeout: Duration) -> Option<MuxEvent> {
+        let buf = vec![0u8; self.max_payload + 5];
+        let (mbuf, amount) = self.sc.try_read_safe(&buf, timeout).expect("read error")?;
+        if amount == 0 {
+            return Some(MuxEvent::End);
+        }
+        if amount < 5 {
+            return None;
+        }
+        let id = u32::from_be_bytes([mbuf[1], mbuf[2], mbuf[3], mbuf[4]]);
+        let payload = &mbuf[5..amount];
+        match mbuf[0] {
+            OPEN => {
+                self.streams.insert(id, Stream::new());
+                Some(MuxEvent::Opened(id))
+            }
+            DATA => {
+                if self.streams.contains_key(&id) {
+                    Some(MuxEvent::Data(id, payload.to_vec()))
+                } else {
+                    None
+                }
+            }
+            CLOSE => {
+                let s = self.streams.get_mut(&id)?;
+                s.remote_closed = true;
+                if s.local_closed {
+                    self.streams.remove(&id);
+                }
+                Some(MuxEvent::Closed(id))
+            }
+            WINDOW if payload.len() == 4 => {
+                if let Some(s) = self.streams.get_mut(&id) {
+                    s.send_window += u32::from_be_bytes(payload.try_into().unwrap()) as usize;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn write_frame(&mut self, kind: u8, id: u32, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(payload.len() + 5);
+        frame.push(kind);
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.sc.write_safe(&frame, self.delay).expect("send error");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        thread,
+    };
+
+    use super::*;
+    use crate::unix_millis;
+
+    /// Two ends of a session over loopback.
+    fn pair() -> (Mux, Mux) {
+        let a = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let b = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        let ends =
+            [a, b].map(|socket| thread::spawn(|| Mux::new(SafeReadWrite::new(socket), 1024, 0)));
+        let [a, b] = ends.map(|end| end.join().unwrap());
+        (a, b)
+    }
+
+    /// The next event, skipping flow control.
+    fn next(mux: &mut Mux) -> MuxEvent {
+        let start = unix_millis();
+        while unix_millis() - start < 2000 {
+            if let Some(event) = mux.poll(Duration::from_millis(10)) {
+                return event;
+            }
+        }
+        panic!("no event");
+    }
+
+    /// Polls for a while, for frames which give no event.
+    fn idle(mux: &mut Mux) {
+        let start = unix_millis();
+        while unix_millis() - start < 200 {
+            assert!(mux.poll(Duration::from_millis(10)).is_none());
+        }
+    }
+
+    #[test]
+    fn open_and_close() {
+        let (mut a, mut b) = pair();
+        let id = a.open();
+        assert!(matches!(next(&mut b), MuxEvent::Opened(opened) if opened == id));
+        // each end has its own ids
+        assert_ne!(b.open() % 2, id % 2);
+        assert!(matches!(next(&mut a), MuxEvent::Opened(_)));
+        assert_eq!(a.open(), id + 2);
+        assert!(matches!(next(&mut b), MuxEvent::Opened(_)));
+
+        // a frame's worth of data is split
+        let data = vec![7u8; a.max_payload() + 1];
+        a.write(id, &data);
+        let mut got = vec![];
+        while got.len() < data.len() {
+            match next(&mut b) {
+                MuxEvent::Data(stream, chunk) if stream == id => got.extend(chunk),
+                _ => panic!("expected data"),
+            }
+        }
+        assert_eq!(got, data);
+
+        // closing one side still lets the other one write
+        a.close(id);
+        assert_eq!(a.send_window(id), 0);
+        assert!(matches!(next(&mut b), MuxEvent::Closed(closed) if closed == id));
+        assert_eq!(b.send_window(id), INITIAL_WINDOW);
+        b.write(id, b"late");
+        assert!(
+            matches!(next(&mut a), MuxEvent::Data(stream, data) if stream == id && data == b"late")
+        );
+        b.close(id);
+        assert!(matches!(next(&mut a), MuxEvent::Closed(closed) if closed == id));
+        assert!(!a.streams.contains_key(&id));
+        assert!(!b.streams.contains_key(&id));
+    }
+
+    #[test]
+    fn flow_control_windows() {
+        let (mut a, mut b) = pair();
+        let id = a.open();
+        assert!(matches!(next(&mut b), MuxEvent::Opened(_)));
+        a.write(id, &[1; 1000]);
+        assert_eq!(a.send_window(id), INITIAL_WINDOW - 1000);
+        assert!(matches!(next(&mut b), MuxEvent::Data(_, data) if data.len() == 1000));
+
+        // credit is only handed out in quarters of the window
+        b.consumed(id, INITIAL_WINDOW / 4 - 1);
+        idle(&mut a);
+        assert_eq!(a.send_window(id), INITIAL_WINDOW - 1000);
+        b.consumed(id, 1);
+        idle(&mut a);
+        assert_eq!(
+            a.send_window(id),
+            INITIAL_WINDOW - 1000 + INITIAL_WINDOW / 4
+        );
+        assert_eq!(b.streams[&id].consumed, 0);
+    }
+
+    #[test]
+    fn end_of_session() {
+        let (a, mut b) = pair();
+        let closing = thread::spawn(move || {
+            let mut a = a;
+            a.sc.close_write();
+        });
+        assert!(matches!(next(&mut b), MuxEvent::End));
+        closing.join().unwrap();
+    }
+}
@@ -0,0 +1,606 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    canonical_addr, guard,
+    protocol::{self, Kind, Message, Protocol, Role},
+    sha256,
+    shard::Shards,
+    unix_millis,
+};
+
+/// Sent to a waiting peer which the helper forgot, because it waited too long or the helper had
+/// to make room (as an error if it registered with protocol v2). Newer clients register again,
+/// older ones ignore it.
+pub const EXPIRED: &[u8] = b"qft-expired";
+
+/// Candidate lists are sent right before the phrase, so they don't need to be kept for long.
+const CANDIDATES_TTL_MS: u64 = 10_000;
+
+/// Waiting peers with protocol v2 hear from the helper this often, which keeps their NAT's mapping
+/// open. Only peers which registered again within KEEPALIVE_WHILE_MS get them, so a forged
+/// registration can't make the helper send much to someone else.
+const KEEPALIVE_MS: u64 = 10_000;
+const KEEPALIVE_WHILE_MS: u64 = 30_000;
+
+/// Peers are only paired with peers of the same address family, dual-stack peers register once
+/// per family. They are also only paired with peers which reached the helper on the same of its
+/// sockets, because each must hear from the address it sent to. Phrases are hashed, so they can
+/// be of any length and legacy and v2 peers still meet.
+pub type Key = ([u8; 32], bool, usize);
+
+/// The key `addr` registers `phrase` under, on the helper's socket number `via`.
+pub fn key(phrase: &[u8], addr: SocketAddr, via: usize) -> Key {
+    // legacy phrases are padded with zeros
+    let end = phrase.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    (
+        sha256::sha256(&phrase[..end]),
+        canonical_addr(addr).is_ipv6(),
+        via,
+    )
+}
+
+struct Waiting {
+    addr: SocketAddr,
+    protocol: Protocol,
+    role: Option<Role>,
+    file_size: Option<u64>,
+    candidates: Option<Vec<u8>>,
+    // how many bytes it sent to register
+    sent: usize,
+    nameplate: Option<u32>,
+    since: u64,
+}
+
+/// A peer asking for its partner.
+pub struct Registration {
+    pub addr: SocketAddr,
+    pub key: Key,
+    pub protocol: Protocol,
+    /// Only protocol v2 tells the role and file size.
+    pub role: Option<Role>,
+    pub file_size: Option<u64>,
+    /// Protocol v2 sends the candidate list along, legacy peers in a packet before.
+    pub candidates: Option<Vec<u8>>,
+    /// The size of the packet(s).
+    pub sent: usize,
+    /// The nameplate the helper gave this peer for its code, only sent by the peer which asked
+    /// for it.
+    pub nameplate: Option<u32>,
+}
+
+/// Two peers which registered the same phrase. The fields are pairs of the new peer's and the
+/// partner's.
+pub struct Pair {
+    pub partner: SocketAddr,
+    pub protocols: (Protocol, Protocol),
+    pub candidates: (Option<Vec<u8>>, Option<Vec<u8>>),
+    pub file_sizes: (Option<u64>, Option<u64>),
+    /// How many bytes they sent to register. The helper's answers must not be bigger.
+    pub sent: (usize, usize),
+    pub nameplates: (Option<u32>, Option<u32>),
+}
+
+pub enum Outcome {
+    /// Registered or renewed, the partner isn't there yet.
+    Waiting,
+    Paired(Pair),
+    /// Too many peers from the same IP are waiting.
+    Refused,
+    /// Someone with this role is waiting for the phrase, which isn't the partner the new peer
+    /// needs. It stays and the new peer isn't registered.
+    Conflict(Role),
+}
+
+/// How many peers from each IP are waiting, over all shards of the pairings.
+pub type PerIp = Arc<Shards<HashMap<IpAddr, usize>>>;
+
+/// The helper's list of peers waiting for their partner, or one shard of it. Entries expire after
+/// a while (sending the phrase again keeps them alive), and the list has a size limit, so abandoned
+/// phrases or someone sending random ones can't fill up the helper's memory.
+pub struct Pairings {
+    ttl: u64,
+    max_waiting: usize,
+    max_per_ip: usize,
+    waiting: HashMap<Key, Waiting>,
+    per_ip: PerIp,
+    // registrations in the order they came in, for expiring and evicting the oldest. Entries which
+    // were renewed or paired since stay in here until they reach the front.
+    order: VecDeque<(Key, u64)>,
+    last_sweep: u64,
+    last_keepalive: u64,
+    expired: u64,
+}
+
+impl Pairings {
+    pub fn new(ttl: Duration, max_waiting: usize, max_per_ip: usize, per_ip: PerIp) -> Pairings {
+        Pairings {
+            ttl: ttl.as_millis() as u64,
+            max_waiting,
+            max_per_ip,
+            waiting: HashMap::new(),
+            per_ip,
+            order: VecDeque::new(),
+            last_sweep: 0,
+            last_keepalive: 0,
+            expired: 0,
+        }
+    }
+
+    /// Registers a peer, and returns its partner if that was already waiting.
+    pub fn register(&mut self, sockets: &[UdpSocket], registration: Registration) -> Outcome {
+        let now = unix_millis();
+        let Registration {
+            addr,
+            key,
+            protocol,
+            role,
+            file_size,
+            candidates,
+            sent,
+            nameplate,
+        } = registration;
+        match self.waiting.get_mut(&key) {
+            Some(waiting) if waiting.addr == addr => {
+                // sent again, that's not a partner, but it is still there
+                if candidates.is_some() {
+                    waiting.candidates = candidates;
+                    waiting.sent = sent;
+                }
+                waiting.protocol = protocol;
+                waiting.role = role;
+                waiting.file_size = file_size;
+                waiting.nameplate = nameplate;
+                // renewing every time would let the order grow with each packet
+                if now - waiting.since >= 1000 {
+                    waiting.since = now;
+                    self.order.push_back((key, now));
+                }
+                Outcome::Waiting
+            }
+            Some(Waiting {
+                role: Some(waiting),
+                ..
+            }) if role.is_some_and(|role| role.partner() != *waiting) => {
+                Outcome::Conflict(*waiting)
+            }
+            Some(_) => {
+                let partner = self.remove(&key);
+                Outcome::Paired(Pair {
+                    partner: partner.addr,
+                    protocols: (protocol, partner.protocol),
+                    candidates: (candidates, partner.candidates),
+                    file_sizes: (file_size, partner.file_size),
+                    sent: (sent, partner.sent),
+                    nameplates: (nameplate, partner.nameplate),
+                })
+            }
+            None => {
+                // so nobody can take up the whole list
+                {
+                    let mut per_ip = self.per_ip.get(&addr.ip());
+                    let count = per_ip.entry(addr.ip()).or_insert(0);
+                    if *count >= self.max_per_ip {
+                        return Outcome::Refused;
+                    }
+                    *count += 1;
+                }
+                if self.waiting.len() >= self.max_waiting {
+                    self.expire_oldest(sockets, u64::MAX);
+                }
+                self.waiting.insert(
+                    key,
+                    Waiting {
+                        addr,
+                        protocol,
+                        role,
+                        file_size,
+                        candidates,
+                        sent,
+                        nameplate,
+                        since: now,
+                    },
+                );
+                self.order.push_back((key, now));
+                Outcome::Waiting
+            }
+        }
+    }
+
+    /// Forgets the oldest registration if it is older than `before`. Returns false if there was
+    /// none.
+    fn expire_oldest(&mut self, sockets: &[UdpSocket], before: u64) -> bool {
+        while let Some((key, since)) = self.order.front().copied() {
+            if since >= before {
+                return false;
+            }
+            self.order.pop_front();
+            // renewed or paired since
+            if self.waiting.get(&key).map(|w| w.since) != Some(since) {
+                continue;
+            }
+            let waiting = self.remove(&key);
+            self.expired += 1;
+            let notice = match waiting.protocol {
+                Protocol::Legacy => EXPIRED.to_vec(),
+                Protocol::V2 => {
+                    Message::error(protocol::EXPIRED, "waited too long for the partner").encode()
+                }
+            };
+            guard::reply(&sockets[key.2], &notice, waiting.sent, waiting.addr);
+            return true;
+        }
+        false
+    }
+
+    fn remove(&mut self, key: &Key) -> Waiting {
+        let waiting = self.waiting.remove(key).unwrap();
+        let ip = waiting.addr.ip();
+        let mut per_ip = self.per_ip.get(&ip);
+        *per_ip.get_mut(&ip).unwrap() -= 1;
+        if per_ip[&ip] == 0 {
+            per_ip.remove(&ip);
+        }
+        waiting
+    }
+
+    /// How many peers are waiting.
+    pub fn waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// How many waiting peers were forgotten so far.
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// Forgets registrations which are too old and sends the keepalives, at most once a second.
+    pub fn sweep(&mut self, sockets: &[UdpSocket]) {
+        let now = unix_millis();
+        if now - self.last_sweep < 1000 {
+            return;
+        }
+        self.last_sweep = now;
+        while self.expire_oldest(sockets, now.saturating_sub(self.ttl)) {}
+        if now - self.last_keepalive >= KEEPALIVE_MS {
+            self.last_keepalive = now;
+            let keepalive = Message::new(Kind::Keepalive).encode();
+            for (key, waiting) in &self.waiting {
+                if waiting.protocol == Protocol::V2 && now - waiting.since < KEEPALIVE_WHILE_MS {
+                    guard::reply(&sockets[key.2], &keepalive, waiting.sent, waiting.addr);
+                }
+            }
+        }
+    }
+}
+
+/// Candidate lists which legacy peers send right before their phrase, with the size of the
+/// packet.
+pub struct Candidates {
+    max: usize,
+    lists: HashMap<SocketAddr, (Vec<u8>, usize, u64)>,
+}
+
+impl Candidates {
+    pub fn new(max: usize) -> Candidates {
+        Candidates {
+            max,
+            lists: HashMap::new(),
+        }
+    }
+
+    /// Remembers the list `addr` sent. The packet is padded with empty lines, those aren't passed
+    /// on.
+    pub fn insert(&mut self, addr: SocketAddr, list: &[u8], sent: usize) {
+        if self.lists.len() < self.max || self.lists.contains_key(&addr) {
+            let end = list.iter().rposition(|b| *b != b'\n').map_or(0, |i| i + 1);
+            let mut list = list[..end].to_vec();
+            list.push(b'\n');
+            self.lists.insert(addr, (list, sent, unix_millis()));
+        }
+    }
+
+    /// The list `addr` sent, and the size of its packet.
+    pub fn take(&mut self, addr: SocketAddr) -> Option<(Vec<u8>, usize)> {
+        self.lists.remove(&addr).map(|(list, sent, _)| (list, sent))
+    }
+
+    pub fn sweep(&mut self) {
+        let now = unix_millis();
+        self.lists
+            .retain(|_, (_, _, time)| now - *time < CANDIDATES_TTL_MS);
+    }
+}
+
+/// The numbers the helper hands out for codes like `7-purple-sausage`, the smallest free one each
+/// time, so codes stay short. A number stays taken while the peer which asked for it waits, and is
+/// free again once it is paired or after the TTL. Only peers from the IP which asked for a number
+/// can keep or free it, and each IP can only take a few, like it can only have a few peers waiting.
+pub struct Nameplates {
+    ttl: u64,
+    max: usize,
+    max_per_ip: usize,
+    // number, and who asked for it and when it was handed out or last used
+    taken: BTreeMap<u32, (IpAddr, u64)>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Nameplates {
+    pub fn new(ttl: Duration, max: usize, max_per_ip: usize) -> Nameplates {
+        Nameplates {
+            ttl: ttl.as_millis() as u64,
+            max,
+            max_per_ip,
+            taken: BTreeMap::new(),
+            per_ip: HashMap::new(),
+        }
+    }
+
+    /// A free number for `ip`, None if too many are taken, in total or by `ip`.
+    pub fn allocate(&mut self, ip: IpAddr) -> Option<u32> {
+        let count = self.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.taken.len() >= self.max || count >= self.max_per_ip {
+            return None;
+        }
+        self.per_ip.insert(ip, count + 1);
+        // the first gap in 1, 2, 3...
+        let free = (1..)
+            .zip(self.taken.keys())
+            .find(|(n, taken)| n != *taken)
+            .map_or(self.taken.len() as u32 + 1, |(n, _)| n);
+        self.taken.insert(free, (ip, unix_millis()));
+        Some(free)
+    }
+
+    /// The peer at `ip` waits with `nameplate`, it stays taken.
+    pub fn renew(&mut self, nameplate: u32, ip: IpAddr) {
+        if let Some((owner, time)) = self.taken.get_mut(&nameplate) {
+            if *owner == ip {
+                *time = unix_millis();
+            }
+        }
+    }
+
+    /// The peer at `ip` was paired, `nameplate` is free again.
+    pub fn release(&mut self, nameplate: u32, ip: IpAddr) {
+        if self
+            .taken
+            .get(&nameplate)
+            .is_some_and(|(owner, _)| *owner == ip)
+        {
+            self.taken.remove(&nameplate);
+            Nameplates::forget(&mut self.per_ip, ip);
+        }
+    }
+
+    pub fn sweep(&mut self) {
+        let now = unix_millis();
+        let per_ip = &mut self.per_ip;
+        self.taken.retain(|_, (ip, time)| {
+            let keep = now - *time < self.ttl;
+            if !keep {
+                Nameplates::forget(per_ip, *ip);
+            }
+            keep
+        });
+    }
+
+    fn forget(per_ip: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+        if let Some(count) = per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairings(ttl: Duration, max_waiting: usize, max_per_ip: usize) -> Pairings {
+        let per_ip = Arc::new(Shards::new(1, HashMap::new));
+        Pairings::new(ttl, max_waiting, max_per_ip, per_ip)
+    }
+
+    fn registration(phrase: &[u8], addr: SocketAddr, role: Option<Role>) -> Registration {
+        Registration {
+            addr,
+            key: key(phrase, addr, 0),
+            protocol: Protocol::V2,
+            role,
+            file_size: None,
+            candidates: None,
+            sent: protocol::REGISTER_SIZE,
+            nameplate: None,
+        }
+    }
+
+    fn sockets() -> Vec<UdpSocket> {
+        vec![UdpSocket::bind("127.0.0.1:0").unwrap()]
+    }
+
+    #[test]
+    fn pairs_complementary_roles() {
+        let (sockets, a, b) = (sockets(), "192.0.2.1:4000", "192.0.2.2:4000");
+        let mut pairings = pairings(Duration::from_secs(60), 10, 10);
+        let sender = registration(b"phrase", a.parse().unwrap(), Some(Role::Sender));
+        assert!(matches!(
+            pairings.register(&sockets, sender),
+            Outcome::Waiting
+        ));
+        // sent again
+        let sender = registration(b"phrase", a.parse().unwrap(), Some(Role::Sender));
+        assert!(matches!(
+            pairings.register(&sockets, sender),
+            Outcome::Waiting
+        ));
+        assert_eq!(pairings.waiting(), 1);
+        let receiver = registration(b"phrase", b.parse().unwrap(), Some(Role::Receiver));
+        let Outcome::Paired(pair) = pairings.register(&sockets, receiver) else {
+            panic!("not paired");
+        };
+        assert_eq!(pair.partner, a.parse().unwrap());
+        assert_eq!(pairings.waiting(), 0);
+    }
+
+    #[test]
+    fn conflicting_roles() {
+        let (sockets, a, b) = (sockets(), "192.0.2.1:4000", "192.0.2.2:4000");
+        let mut pairings = pairings(Duration::from_secs(60), 10, 10);
+        let sender = registration(b"phrase", a.parse().unwrap(), Some(Role::Sender));
+        pairings.register(&sockets, sender);
+        let other = registration(b"phrase", b.parse().unwrap(), Some(Role::Sender));
+        assert!(matches!(
+            pairings.register(&sockets, other),
+            Outcome::Conflict(Role::Sender)
+        ));
+        assert_eq!(pairings.waiting(), 1);
+        // peers which don't tell their role pair with anyone
+        let legacy = registration(b"phrase", b.parse().unwrap(), None);
+        assert!(matches!(
+            pairings.register(&sockets, legacy),
+            Outcome::Paired(_)
+        ));
+    }
+
+    #[test]
+    fn refuses_too_many_from_one_ip() {
+        let sockets = sockets();
+        let mut pairings = pairings(Duration::from_secs(60), 10, 2);
+        for (phrase, port) in [(&b"one"[..], 4000), (b"two", 4001)] {
+            let registration = registration(phrase, ([192, 0, 2, 1], port).into(), None);
+            assert!(matches!(
+                pairings.register(&sockets, registration),
+                Outcome::Waiting
+            ));
+        }
+        let third = registration(b"three", ([192, 0, 2, 1], 4002).into(), None);
+        assert!(matches!(
+            pairings.register(&sockets, third),
+            Outcome::Refused
+        ));
+        let elsewhere = registration(b"three", ([192, 0, 2, 2], 4002).into(), None);
+        assert!(matches!(
+            pairings.register(&sockets, elsewhere),
+            Outcome::Waiting
+        ));
+    }
+
+    #[test]
+    fn expires_and_tells_the_peer() {
+        let sockets = sockets();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut pairings = pairings(Duration::ZERO, 10, 10);
+        let registration = registration(b"phrase", peer.local_addr().unwrap(), None);
+        pairings.register(&sockets, registration);
+        std::thread::sleep(Duration::from_millis(5));
+        pairings.sweep(&sockets);
+        assert_eq!(pairings.waiting(), 0);
+        assert_eq!(pairings.expired(), 1);
+        let mut buf = [0u8; 2048];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(
+            Message::decode(&buf[..len]).unwrap().code(),
+            Some(protocol::EXPIRED)
+        );
+    }
+
+    #[test]
+    fn makes_room_when_full() {
+        let sockets = sockets();
+        let mut pairings = pairings(Duration::from_secs(60), 1, 10);
+        let first = registration(b"one", "192.0.2.1:4000".parse().unwrap(), None);
+        pairings.register(&sockets, first);
+        let second = registration(b"two", "192.0.2.2:4000".parse().unwrap(), None);
+        assert!(matches!(
+            pairings.register(&sockets, second),
+            Outcome::Waiting
+        ));
+        assert_eq!(pairings.waiting(), 1);
+        assert_eq!(pairings.expired(), 1);
+        // the first one is gone, its phrase waits anew
+        let again = registration(b"one", "192.0.2.3:4000".parse().unwrap(), None);
+        assert!(matches!(
+            pairings.register(&sockets, again),
+            Outcome::Waiting
+        ));
+    }
+
+    #[test]
+    fn legacy_phrases_meet_v2_ones() {
+        let mut padded = b"phrase".to_vec();
+        padded.resize(200, 0);
+        let addr = "192.0.2.1:4000".parse().unwrap();
+        assert_eq!(key(&padded, addr, 0), key(b"phrase", addr, 0));
+        assert_ne!(key(b"phrase", addr, 0), key(b"phrase", addr, 1));
+        let v6 = "[2001:db8::1]:4000".parse().unwrap();
+        assert_ne!(key(b"phrase", addr, 0), key(b"phrase", v6, 0));
+        // IPv4 on a dual-stack socket is still IPv4
+        let mapped = "[::ffff:192.0.2.1]:4000".parse().unwrap();
+        assert_eq!(key(b"phrase", addr, 0), key(b"phrase", mapped, 0));
+    }
+
+    #[test]
+    fn nameplates_stay_small() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let mut nameplates = Nameplates::new(Duration::from_secs(60), 10, 10);
+        assert_eq!(nameplates.allocate(ip), Some(1));
+        assert_eq!(nameplates.allocate(ip), Some(2));
+        assert_eq!(nameplates.allocate(ip), Some(3));
+        nameplates.release(2, ip);
+        assert_eq!(nameplates.allocate(ip), Some(2));
+        assert_eq!(nameplates.allocate(ip), Some(4));
+    }
+
+    #[test]
+    fn nameplates_belong_to_their_ip() {
+        let (ip, other) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+        let mut nameplates = Nameplates::new(Duration::from_secs(60), 10, 10);
+        assert_eq!(nameplates.allocate(ip), Some(1));
+        nameplates.release(1, other);
+        assert_eq!(nameplates.allocate(other), Some(2));
+        nameplates.release(1, ip);
+        assert_eq!(nameplates.allocate(other), Some(1));
+    }
+
+    #[test]
+    fn nameplates_run_out_and_expire() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let mut nameplates = Nameplates::new(Duration::from_secs(60), 2, 10);
+        assert!(nameplates.allocate(ip).is_some());
+        assert!(nameplates.allocate(ip).is_some());
+        assert_eq!(nameplates.allocate(ip), None);
+        let mut nameplates = Nameplates::new(Duration::ZERO, 2, 10);
+        nameplates.allocate(ip);
+        nameplates.allocate(ip);
+        nameplates.sweep();
+        assert_eq!(nameplates.allocate(ip), Some(1));
+    }
+
+    #[test]
+    fn nameplates_per_ip() {
+        let (ip, other) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+        let mut nameplates = Nameplates::new(Duration::from_secs(60), 10, 2);
+        assert_eq!(nameplates.allocate(ip), Some(1));
+        assert_eq!(nameplates.allocate(ip), Some(2));
+        assert_eq!(nameplates.allocate(ip), None);
+        // everyone else still gets one
+        assert_eq!(nameplates.allocate(other), Some(3));
+        // and freeing one makes room again
+        nameplates.release(1, ip);
+        assert_eq!(nameplates.allocate(ip), Some(1));
+        assert_eq!(nameplates.allocate(ip), None);
+        let mut nameplates = Nameplates::new(Duration::ZERO, 10, 2);
+        nameplates.allocate(ip);
+        nameplates.allocate(ip);
+        nameplates.sweep();
+        assert_eq!(nameplates.allocate(ip), Some(1));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    io::{stdin, stdout, Read, Write},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, TryRecvError},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    holepunch, protocol::Role, unix_millis, HolepunchError, SafeReadWrite, STDOUT_IS_DATA,
+};
+
+/// Connects stdin and stdout of both partners, like netcat does. Both ends run the same command.
+/// Each direction is closed separately: when our stdin ends, the partner's stdout ends, but we keep
+/// printing what the partner sends until its stdin ends as well.
+pub fn pipe(args: &[String]) -> Result<(), HolepunchError> {
+    STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+    let connection = holepunch(args, Role::Pipe, None)?;
+    let dly = args
+        .get(4)
+        .map(|s| s.parse::<u64>())
+        .unwrap_or(Ok(500))
+        .expect("bad delay operand");
+    let br = args
+        .get(5)
+        .map(|s| s.parse::<u32>())
+        .unwrap_or(Ok(256))
+        .expect("bad bitrate argument");
+
+    // stdin can only be read blocking, so it gets its own thread. An empty Vec means EOF.
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let mut stdin = stdin();
+        let mut buf = vec![0u8; br as usize];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    let _ = tx.send(vec![]);
+                    return;
+                }
+                Ok(read) => {
+                    if tx.send(buf[..read].to_vec()).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    copy(SafeReadWrite::new(connection), rx, br, dly, &mut stdout());
+    eprintln!("Pipe closed. Thank you!");
+    Ok(())
+}
+
+/// Sends what arrives through `rx` to the partner and writes what the partner sends to `out`,
+/// until both sides are done. An empty Vec from `rx` closes our side.
+fn copy(
+    mut sc: SafeReadWrite,
+    rx: mpsc::Receiver<Vec<u8>>,
+    br: u32,
+    dly: u64,
+    out: &mut impl Write,
+) {
+    let buf = vec![0u8; br as usize];
+    let mut local_done = false;
+    let mut remote_done = false;
+    while !local_done || !remote_done {
+        while !local_done {
+            match rx.try_recv() {
+                Ok(data) if data.is_empty() => {
+                    sc.close_write();
+                    local_done = true;
+                }
+                Ok(data) => sc.write_safe(&data, dly).expect("send error"),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => local_done = true,
+            }
+        }
+        // keep reading even when the partner is done, Acks for our own data arrive here too.
+        match sc
+            .try_read_safe(&buf, Duration::from_millis(10))
+            .expect("read error")
+        {
+            Some((_, 0)) => {
+                if !remote_done {
+                    eprintln!("Partner closed its side of the pipe.");
+                }
+                remote_done = true;
+            }
+            Some((mbuf, amount)) if !remote_done => {
+                out.write_all(&mbuf[..amount]).expect("write error");
+                out.flush().expect("flush error");
+            }
+            Some(_) | None => (),
+        }
+    }
+    // the partner may still miss our last packets, which try_read_safe sends again
+    let give_up = unix_millis() + 5000;
+    while sc.unacknowledged() && unix_millis() < give_up {
+        let _ = sc.try_read_safe(&buf, Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use super::*;
+
+    fn data(seed: u8) -> Vec<u8> {
+        (0..100_000u32)
+            .map(|i| (i % 251) as u8 ^ seed)
+            .collect::<Vec<u8>>()
+    }
+
+    #[test]
+    fn both_sides_write_at_once() {
+        let a = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let b = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        let ends = [(a, 1), (b, 2)].map(|(socket, seed)| {
+            thread::spawn(move || {
+                let (tx, rx) = mpsc::channel();
+                for chunk in data(seed).chunks(256) {
+                    tx.send(chunk.to_vec()).unwrap();
+                }
+                tx.send(vec![]).unwrap();
+                let mut out = Vec::new();
+                copy(SafeReadWrite::new(socket), rx, 256, 500, &mut out);
+                out
+            })
+        });
+        let [a, b] = ends.map(|end| end.join().unwrap());
+        assert!(a == data(2), "a got {} bytes", a.len());
+        assert!(b == data(1), "b got {} bytes", b.len());
+    }
+}
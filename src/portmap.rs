@@ -0,0 +1,542 @@
+use std::{
+    env,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use std::fs;
+
+use crate::{nonce, unix_millis};
+
+/// Where PCP and NAT-PMP servers listen, on the gateway.
+const PCP_PORT: u16 = 5351;
+const SSDP_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+/// Seconds a mapping is requested for. It is renewed at half of that while qft runs, so a crash
+/// doesn't leave it behind for long.
+const LIFETIME: u32 = 3600;
+
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_UNSUPP_VERSION: u8 = 1;
+const NATPMP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_MAP_UDP: u8 = 1;
+const UDP: u8 = 17;
+
+const UPNP_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+enum Protocol {
+    /// RFC 6887. The nonce identifies our mapping towards the server.
+    Pcp([u8; 12]),
+    /// RFC 6886, the predecessor of PCP.
+    NatPmp,
+    /// UPnP Internet Gateway Device, with the URL and type of the service that maps ports.
+    Upnp { control: String, service: String },
+}
+
+struct PortMapping {
+    protocol: Protocol,
+    gateway: SocketAddr,
+    /// Our local address, which the router forwards to.
+    internal: SocketAddr,
+    /// The address on the router that the partner can send to.
+    external: SocketAddr,
+}
+
+impl PortMapping {
+    fn protocol_name(&self) -> &'static str {
+        match self.protocol {
+            Protocol::Pcp(_) => "PCP",
+            Protocol::NatPmp => "NAT-PMP",
+            Protocol::Upnp { .. } => "UPnP",
+        }
+    }
+}
+
+static MAPPINGS: Mutex<Vec<PortMapping>> = Mutex::new(Vec::new());
+static RENEWING: AtomicBool = AtomicBool::new(false);
+
+/// Asks the router to forward a public UDP port to `socket`, using PCP, NAT-PMP or UPnP (whichever
+/// it supports). Returns the public address. Only works for IPv4, where routers do NAT.
+/// QFT_PORT_MAPPING may name the gateway (for example a mock one on 127.0.0.1:5351), otherwise
+/// the default route's gateway is used.
+pub fn map(socket: &UdpSocket, helper: SocketAddr) -> Option<SocketAddr> {
+    if helper.is_ipv6() {
+        return None;
+    }
+    let port = socket.local_addr().unwrap().port();
+    let gateway = configured_gateway().or_else(default_gateway)?;
+    // the address of the interface which leads to the gateway is the one to forward to
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    probe.connect(gateway).ok()?;
+    let internal = SocketAddr::new(probe.local_addr().ok()?.ip(), port);
+
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[..8].copy_from_slice(&nonce().to_be_bytes());
+    let mut mapping = PortMapping {
+        protocol: Protocol::Pcp(nonce_bytes),
+        gateway,
+        internal,
+        external: internal,
+    };
+    let external = match request(&mut mapping, LIFETIME) {
+        Some(external) => Some(external),
+        None => upnp_discover(internal.ip()).and_then(|(control, service)| {
+            mapping.protocol = Protocol::Upnp { control, service };
+            request(&mut mapping, LIFETIME)
+        }),
+    };
+    let Some(external) = external else {
+        eprintln!("Your router didn't map a port (it might not support PCP, NAT-PMP or UPnP).");
+        return None;
+    };
+    mapping.external = external;
+    eprintln!(
+        "Your router forwards {} to you ({}).",
+        external,
+        mapping.protocol_name()
+    );
+    MAPPINGS.lock().unwrap().push(mapping);
+    if !RENEWING.swap(true, Ordering::SeqCst) {
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_secs(LIFETIME as u64 / 2));
+            for mapping in MAPPINGS.lock().unwrap().iter_mut() {
+                if let Some(external) = request(mapping, LIFETIME) {
+                    mapping.external = external;
+                }
+            }
+        });
+    }
+    Some(external)
+}
+
+/// Removes all mappings made by `map`, called after the transfer.
+pub fn unmap_all() {
+    // a panic while the lock was held doesn't make the mappings any less worth removing
+    let mut mappings = MAPPINGS.lock().unwrap_or_else(|e| e.into_inner());
+    for mut mapping in mappings.drain(..) {
+        if request(&mut mapping, 0).is_some() {
+            eprintln!(
+                "Removed the port mapping {} from your router.",
+                mapping.external
+            );
+        }
+    }
+}
+
+/// Calls `unmap_all` when dropped, so mappings are removed even if qft panics before it is done.
+pub struct Unmap;
+
+impl Drop for Unmap {
+    fn drop(&mut self) {
+        unmap_all();
+    }
+}
+
+fn configured_gateway() -> Option<SocketAddr> {
+    let value = env::var("QFT_PORT_MAPPING").ok()?;
+    if let Ok(ip) = Ipv4Addr::from_str(&value) {
+        return Some((ip, PCP_PORT).into());
+    }
+    value.to_socket_addrs().ok()?.find(|a| a.is_ipv4())
+}
+
+/// Reads the gateway of the default route from /proc. Elsewhere, guess that the router has the
+/// first address of our network, which most home routers do.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<SocketAddr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    for line in routes.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        // Iface Destination Gateway ..., addresses as little-endian hex
+        if columns.len() > 2 && columns[1] == "00000000" {
+            let gateway = u32::from_str_radix(columns[2], 16).ok()?;
+            return Some((Ipv4Addr::from(gateway.swap_bytes()), PCP_PORT).into());
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<SocketAddr> {
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    probe.connect((Ipv4Addr::new(1, 1, 1, 1), 53)).ok()?;
+    let IpAddr::V4(ip) = probe.local_addr().ok()?.ip() else {
+        return None;
+    };
+    let o = ip.octets();
+    Some((Ipv4Addr::new(o[0], o[1], o[2], 1), PCP_PORT).into())
+}
+
+/// Creates, renews (same request again) or with a lifetime of 0 deletes a mapping. Switches from
+/// PCP to NAT-PMP if the router only speaks that.
+fn request(mapping: &mut PortMapping, lifetime: u32) -> Option<SocketAddr> {
+    match &mapping.protocol {
+        Protocol::Pcp(nonce) => {
+            let nonce = *nonce;
+            let response = exchange(
+                mapping.gateway,
+                mapping.internal.ip(),
+                &pcp_request(&nonce, mapping, lifetime),
+            )?;
+            // NAT-PMP servers answer unknown versions with their own version (0)
+            if response[0] == 0 || (response.len() >= 4 && response[3] == PCP_UNSUPP_VERSION) {
+                mapping.protocol = Protocol::NatPmp;
+                return request(mapping, lifetime);
+            }
+            if response.len() < 60
+                || response[0] != PCP_VERSION
+                || response[1] != (0x80 | PCP_MAP)
+                || response[3] != 0
+                || response[24..36] != nonce
+            {
+                return None;
+            }
+            if lifetime == 0 {
+                return Some(mapping.external);
+            }
+            let port = u16::from_be_bytes([response[42], response[43]]);
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&response[44..60]).unwrap());
+            Some(SocketAddr::new(IpAddr::V4(ip.to_ipv4_mapped()?), port))
+        }
+        Protocol::NatPmp => {
+            // the mapping response has no address, it needs to be asked for separately
+            let address = exchange(
+                mapping.gateway,
+                mapping.internal.ip(),
+                &[0, NATPMP_EXTERNAL_ADDRESS],
+            )?;
+            let mut map_request = vec![0, NATPMP_MAP_UDP, 0, 0];
+            map_request.extend_from_slice(&mapping.internal.port().to_be_bytes());
+            let suggested = if lifetime == 0 {
+                0
+            } else {
+                mapping.internal.port()
+            };
+            map_request.extend_from_slice(&suggested.to_be_bytes());
+            map_request.extend_from_slice(&lifetime.to_be_bytes());
+            let response = exchange(mapping.gateway, mapping.internal.ip(), &map_request)?;
+            if address.len() < 12
+                || address[1] != (0x80 | NATPMP_EXTERNAL_ADDRESS)
+                || address[2..4] != [0, 0]
+                || response.len() < 16
+                || response[1] != (0x80 | NATPMP_MAP_UDP)
+                || response[2..4] != [0, 0]
+            {
+                return None;
+            }
+            if lifetime == 0 {
+                return Some(mapping.external);
+            }
+            let ip = Ipv4Addr::new(address[8], address[9], address[10], address[11]);
+            let port = u16::from_be_bytes([response[10], response[11]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        Protocol::Upnp { control, service } => {
+            let port = mapping.internal.port().to_string();
+            if lifetime == 0 {
+                let args = format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>UDP</NewProtocol>",
+                    mapping.external.port()
+                );
+                soap(control, service, "DeletePortMapping", &args)?;
+                return Some(mapping.external);
+            }
+            let add = |lease: u32| {
+                let args = format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>UDP</NewProtocol><NewInternalPort>{}</NewInternalPort>\
+                     <NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled>\
+                     <NewPortMappingDescription>qft</NewPortMappingDescription>\
+                     <NewLeaseDuration>{}</NewLeaseDuration>",
+                    port,
+                    port,
+                    mapping.internal.ip(),
+                    lease
+                );
+                soap(control, service, "AddPortMapping", &args)
+            };
+            // some routers only take permanent mappings, unmap_all removes it anyway
+            add(lifetime).or_else(|| add(0))?;
+            let response = soap(control, service, "GetExternalIPAddress", "")?;
+            let ip = Ipv4Addr::from_str(tag(&response, "NewExternalIPAddress")?.trim()).ok()?;
+            Some(SocketAddr::new(IpAddr::V4(ip), mapping.internal.port()))
+        }
+    }
+}
+
+fn pcp_request(nonce: &[u8; 12], mapping: &PortMapping, lifetime: u32) -> Vec<u8> {
+    let IpAddr::V4(internal_ip) = mapping.internal.ip() else {
+        unreachable!("only IPv4 is mapped");
+    };
+    let mut packet = vec![PCP_VERSION, PCP_MAP, 0, 0];
+    packet.extend_from_slice(&lifetime.to_be_bytes());
+    packet.extend_from_slice(&internal_ip.to_ipv6_mapped().octets());
+    packet.extend_from_slice(nonce);
+    packet.extend_from_slice(&[UDP, 0, 0, 0]);
+    packet.extend_from_slice(&mapping.internal.port().to_be_bytes());
+    // suggest the same port and whichever external address the router has
+    packet.extend_from_slice(&mapping.internal.port().to_be_bytes());
+    packet.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    packet
+}
+
+/// Sends `request` to the gateway and returns its answer. Retransmits a few times, like RFC 6886
+/// suggests, but gives up after about two seconds so a router without support doesn't hold
+/// anything up.
+fn exchange(gateway: SocketAddr, local_ip: IpAddr, request: &[u8]) -> Option<Vec<u8>> {
+    let socket = UdpSocket::bind((local_ip, 0)).ok()?;
+    let mut buf = [0u8; 1100];
+    let mut wait = 250;
+    for _ in 0..3 {
+        socket.send_to(request, gateway).ok()?;
+        let sent = unix_millis();
+        while unix_millis() - sent < wait {
+            socket
+                .set_read_timeout(Some(Duration::from_millis(
+                    wait.saturating_sub(unix_millis() - sent).max(1),
+                )))
+                .ok()?;
+            let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            if from == gateway && len >= 4 {
+                return Some(buf[..len].to_vec());
+            }
+        }
+        wait *= 2;
+    }
+    None
+}
+
+/// Finds the router's port mapping service with SSDP, returns its control URL and service type.
+fn upnp_discover(local_ip: IpAddr) -> Option<(String, String)> {
+    let socket = UdpSocket::bind((local_ip, 0)).ok()?;
+    let search = "M-SEARCH * HTTP/1.1\r\n\
+                  HOST: 239.255.255.250:1900\r\n\
+                  MAN: \"ssdp:discover\"\r\n\
+                  MX: 1\r\n\
+                  ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    socket.send_to(search.as_bytes(), SSDP_ADDR).ok()?;
+    let start = unix_millis();
+    let mut buf = [0u8; 2048];
+    while unix_millis() - start < 1500 {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .ok()?;
+        let Ok(len) = socket.recv(&mut buf) else {
+            continue;
+        };
+        let response = String::from_utf8_lossy(&buf[..len]).to_string();
+        let Some(location) = response.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_owned())
+        }) else {
+            continue;
+        };
+        let Some(description) = http(&location, None) else {
+            continue;
+        };
+        for service in UPNP_SERVICES {
+            let Some(start) = description.find(&format!("<serviceType>{}</serviceType>", service))
+            else {
+                continue;
+            };
+            let Some(control) = tag(&description[start..], "controlURL") else {
+                continue;
+            };
+            return Some((absolute_url(&location, control.trim()), service.to_owned()));
+        }
+    }
+    None
+}
+
+fn absolute_url(base: &str, path: &str) -> String {
+    if path.starts_with("http://") {
+        return path.to_owned();
+    }
+    let rest = base.trim_start_matches("http://");
+    let host = rest.split('/').next().unwrap_or(rest);
+    if path.starts_with('/') {
+        format!("http://{}{}", host, path)
+    } else {
+        format!("http://{}/{}", host, path)
+    }
+}
+
+/// The text inside the first <name>...</name>.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+fn soap(control: &str, service: &str, action: &str, args: &str) -> Option<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>\r\n",
+        action, service, args, action
+    );
+    http(control, Some((&format!("{}#{}", service, action), &body)))
+}
+
+/// A minimal HTTP/1.0 client, enough for talking to routers. Sends a SOAP POST if `soap` is given
+/// (action and body), otherwise a GET. Returns the body of a 200 response.
+fn http(url: &str, soap: Option<(&str, &str)>) -> Option<String> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_socket_addrs().ok()?.next()?
+    } else {
+        (host, 80).to_socket_addrs().ok()?.next()?
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2)).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(3))).ok()?;
+    let request = match soap {
+        Some((action, body)) => format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{}\"\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            host,
+            action,
+            body.len(),
+            body
+        ),
+        None => format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host),
+    };
+    stream.write_all(request.as_bytes()).ok()?;
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response).to_string();
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    if head.lines().next()?.split(' ').nth(1)? != "200" {
+        return None;
+    }
+    Some(body.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A gateway on localhost which answers every request with `answer`.
+    fn gateway(answer: impl Fn(&[u8]) -> Vec<u8> + Send + 'static) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(&answer(&buf[..len]), from);
+            }
+        });
+        addr
+    }
+
+    fn mapping(gateway: SocketAddr) -> PortMapping {
+        PortMapping {
+            protocol: Protocol::Pcp([7; 12]),
+            gateway,
+            internal: (Ipv4Addr::LOCALHOST, 4000).into(),
+            external: (Ipv4Addr::LOCALHOST, 4000).into(),
+        }
+    }
+
+    fn external() -> SocketAddr {
+        "198.51.100.1:40000".parse().unwrap()
+    }
+
+    /// The success response to a PCP MAP request, with `external`.
+    fn pcp_answer(request: &[u8]) -> Vec<u8> {
+        let mut response = request.to_vec();
+        response[1] |= 0x80;
+        response[42..44].copy_from_slice(&external().port().to_be_bytes());
+        let IpAddr::V4(ip) = external().ip() else {
+            unreachable!()
+        };
+        response[44..60].copy_from_slice(&ip.to_ipv6_mapped().octets());
+        response
+    }
+
+    fn natpmp_answer(request: &[u8]) -> Vec<u8> {
+        match request[1] {
+            NATPMP_EXTERNAL_ADDRESS => vec![0, 0x80, 0, 0, 0, 0, 0, 1, 198, 51, 100, 1],
+            _ => {
+                let mut response = vec![0, 0x80 | NATPMP_MAP_UDP, 0, 0, 0, 0, 0, 1];
+                response.extend_from_slice(&request[4..6]);
+                response.extend_from_slice(&external().port().to_be_bytes());
+                response.extend_from_slice(&request[8..12]);
+                response
+            }
+        }
+    }
+
+    #[test]
+    fn pcp_map() {
+        let mut mapping = mapping(gateway(pcp_answer));
+        assert_eq!(request(&mut mapping, LIFETIME), Some(external()));
+        assert!(matches!(mapping.protocol, Protocol::Pcp(_)));
+    }
+
+    #[test]
+    fn pcp_refusals() {
+        let mut refused = mapping(gateway(|request| {
+            let mut response = pcp_answer(request);
+            // NOT_AUTHORIZED
+            response[3] = 2;
+            response
+        }));
+        assert_eq!(request(&mut refused, LIFETIME), None);
+        let mut foreign = mapping(gateway(|request| {
+            let mut response = pcp_answer(request);
+            // somebody else's nonce
+            response[24] ^= 1;
+            response
+        }));
+        assert_eq!(request(&mut foreign, LIFETIME), None);
+        let mut short = mapping(gateway(|request| pcp_answer(request)[..59].to_vec()));
+        assert_eq!(request(&mut short, LIFETIME), None);
+    }
+
+    #[test]
+    fn natpmp_fallback() {
+        let mut mapping = mapping(gateway(|request| match request[0] {
+            PCP_VERSION => vec![0, 0x80 | PCP_MAP, 0, 1],
+            _ => natpmp_answer(request),
+        }));
+        assert_eq!(request(&mut mapping, LIFETIME), Some(external()));
+        assert!(matches!(mapping.protocol, Protocol::NatPmp));
+        // deleting gives back the mapping we had
+        mapping.external = external();
+        assert_eq!(request(&mut mapping, 0), Some(external()));
+    }
+
+    #[test]
+    fn natpmp_refusal() {
+        let mut mapping = mapping(gateway(|request| {
+            let mut response = natpmp_answer(request);
+            // unsupported opcode
+            response[3] = 5;
+            response
+        }));
+        mapping.protocol = Protocol::NatPmp;
+        assert_eq!(request(&mut mapping, LIFETIME), None);
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    ice::{Candidate, CandidateKind},
+    nonce, stun, unix_millis,
+};
+
+const SPRAY_MAGIC: &[u8] = b"qft-spray";
+
+// kinds of spray packets: [SPRAY_MAGIC][kind: u8][tag: u64 BE]
+const PROBE: u8 = 0;
+const ACK: u8 = 1;
+
+/// How far past the last known port a counting NAT is followed. Every new destination (including
+/// the partner's local addresses and other programs' traffic) uses up a port.
+const SEQUENTIAL_RANGE: i32 = 32;
+/// Fresh sockets which ask the helper when it has no second port to ask.
+const FRESH_SOCKETS: usize = 3;
+/// Sockets opened by the side behind a NAT with random ports. Together with the guesses of the
+/// partner, a hit is very likely: 256 mappings and 640 guesses per second give 1 - e^(-256 * 640 /
+/// 65536), over 90%, every second.
+const SOCKETS: usize = 256;
+const GUESSES_PER_ROUND: usize = 64;
+
+/// How a symmetric NAT (one which gives every destination its own public port) picks ports.
+/// NATs which use the same port for every destination don't need any of this.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mapping {
+    /// Each new destination gets the port `delta` from the previous one. `last` is the last one
+    /// we know of.
+    Sequential { last: u16, delta: i32 },
+    /// Each new destination gets an unpredictable port.
+    Random,
+}
+use Mapping::*;
+
+impl Mapping {
+    /// A line of the candidate list, see `CandidateList::encode`.
+    pub fn encode(&self) -> String {
+        match self {
+            Sequential { last, delta } => format!("n s {} {}\n", last, delta),
+            Random => "n r\n".to_owned(),
+        }
+    }
+
+    pub fn decode(value: &str) -> Option<Mapping> {
+        let mut parts = value.split(' ');
+        match parts.next()? {
+            "s" => Some(Sequential {
+                last: parts.next()?.parse().ok()?,
+                delta: parts.next()?.parse().ok()?,
+            }),
+            "r" => Some(Random),
+            _ => None,
+        }
+    }
+}
+
+/// Asks the helper's main and diagnostics ports (and the STUN server, if there is one) which
+/// address they see. If that differs per destination, our NAT is symmetric and this returns how it
+/// picks ports. None means the mapping is the same for everyone.
+///
+/// Helpers without a diagnostics port can only be asked from fresh sockets, which shows how the
+/// NAT picks ports for new mappings, but not whether it keeps a mapping for other destinations.
+/// NATs which keep the socket's own port probably do, so they count as not symmetric.
+pub fn measure(
+    socket: &UdpSocket,
+    helper: SocketAddr,
+    stun_server: Option<SocketAddr>,
+) -> Option<Mapping> {
+    let timeout = Duration::from_secs(1);
+    let first = stun::request(socket, helper, false, timeout).ok()?;
+    let mut destinations = vec![];
+    if let Some(port) = first.other_port {
+        destinations.push(SocketAddr::new(helper.ip(), port));
+    }
+    if let Some(server) = stun_server {
+        if server.is_ipv6() == helper.is_ipv6() {
+            destinations.push(server);
+        }
+    }
+    if destinations.is_empty() {
+        if first.mapped.port() == socket.local_addr().ok()?.port() {
+            return None;
+        }
+        let bind_addr: SocketAddr = if helper.is_ipv6() {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let mut ports = vec![];
+        for _ in 0..FRESH_SOCKETS {
+            let fresh = UdpSocket::bind(bind_addr).ok()?;
+            if let Ok(mapped) = stun::binding(&fresh, helper, timeout) {
+                ports.push(mapped.port());
+            }
+        }
+        return classify(&ports);
+    }
+    let mut ports = vec![first.mapped.port()];
+    for destination in destinations {
+        if let Ok(mapped) = stun::binding(socket, destination, timeout) {
+            ports.push(mapped.port());
+        }
+    }
+    classify(&ports)
+}
+
+/// How a NAT picks ports, from the ones it gave to new mappings one after another. None if they
+/// are all the same, or if there are less than two.
+fn classify(ports: &[u16]) -> Option<Mapping> {
+    if ports.len() < 2 || ports.iter().all(|p| *p == ports[0]) {
+        return None;
+    }
+    let deltas: Vec<i32> = ports
+        .windows(2)
+        .map(|w| w[1] as i32 - w[0] as i32)
+        .collect();
+    let last = *ports.last().unwrap();
+    match deltas[0] {
+        d if d != 0 && d.abs() <= SEQUENTIAL_RANGE && deltas.iter().all(|x| *x == d) => {
+            Some(Sequential { last, delta: d })
+        }
+        _ => Some(Random),
+    }
+}
+
+/// The addresses a counting NAT will probably give its next destinations. Checks sent there open
+/// our own NAT for them, so the partner's checks can get through once its NAT picked one.
+pub fn predicted_candidates(mapping: Mapping, ip: IpAddr) -> Vec<Candidate> {
+    let Sequential { last, delta } = mapping else {
+        return vec![];
+    };
+    (1..=SEQUENTIAL_RANGE)
+        .map(|i| last as i32 + i * delta)
+        .filter(|port| (1..=0xffff).contains(port))
+        .map(|port| Candidate {
+            kind: CandidateKind::ServerReflexive,
+            addr: SocketAddr::new(ip, port as u16),
+        })
+        .collect()
+}
+
+fn packet(kind: u8, tag: u64) -> Vec<u8> {
+    let mut packet = Vec::from(SPRAY_MAGIC);
+    packet.push(kind);
+    packet.extend_from_slice(&tag.to_be_bytes());
+    packet
+}
+
+fn parse(buf: &[u8], tag: u64) -> Option<u8> {
+    if buf.len() != SPRAY_MAGIC.len() + 9
+        || &buf[..SPRAY_MAGIC.len()] != SPRAY_MAGIC
+        || buf[SPRAY_MAGIC.len() + 1..] != tag.to_be_bytes()
+    {
+        return None;
+    }
+    Some(buf[SPRAY_MAGIC.len()])
+}
+
+/// xorshift64, good enough for picking ports
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// For NATs with random ports (birthday paradox): the side behind one (`many_sockets`) opens lots
+/// of sockets which all send to the partner, while the partner sends to lots of random ports.
+/// Each guess has a small chance of hitting one of the mappings, all of them together a very good
+/// one. Returns the socket which got through, connected to the partner.
+pub fn birthday(
+    socket: &UdpSocket,
+    partner: SocketAddr,
+    tag: u64,
+    many_sockets: bool,
+    timeout: Duration,
+) -> Option<UdpSocket> {
+    let bind_addr: SocketAddr = if partner.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let mut sockets = vec![socket.try_clone().expect("unable to clone socket")];
+    if many_sockets {
+        for _ in 0..SOCKETS {
+            // running out of file descriptors just means fewer tries
+            let Ok(extra) = UdpSocket::bind(bind_addr) else {
+                break;
+            };
+            sockets.push(extra);
+        }
+    }
+    for s in &sockets {
+        s.set_nonblocking(true).unwrap();
+    }
+
+    let mut random = nonce() | 1;
+    let mut winner: Option<(usize, SocketAddr)> = None;
+    let start = unix_millis();
+    let mut last_send = 0;
+    let mut buf = [0u8; 64];
+    'outer: while unix_millis() - start < timeout.as_millis() as u64 {
+        if many_sockets && unix_millis() - last_send >= 250 {
+            last_send = unix_millis();
+            for s in &sockets {
+                let _ = s.send_to(&packet(PROBE, tag), partner);
+            }
+        }
+        if !many_sockets && unix_millis() - last_send >= 100 {
+            last_send = unix_millis();
+            for _ in 0..GUESSES_PER_ROUND {
+                let port = 1024 + (next_random(&mut random) % (0x10000 - 1024)) as u16;
+                let _ =
+                    sockets[0].send_to(&packet(PROBE, tag), SocketAddr::new(partner.ip(), port));
+            }
+        }
+        for (i, s) in sockets.iter().enumerate() {
+            while let Ok((len, from)) = s.recv_from(&mut buf) {
+                if from.ip() != partner.ip() {
+                    continue;
+                }
+                match parse(&buf[..len], tag) {
+                    Some(PROBE) => {
+                        for _ in 0..5 {
+                            let _ = s.send_to(&packet(ACK, tag), from);
+                        }
+                        winner = Some((i, from));
+                        break 'outer;
+                    }
+                    Some(ACK) => {
+                        winner = Some((i, from));
+                        break 'outer;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    socket.set_nonblocking(false).unwrap();
+
+    let (i, from) = winner?;
+    let socket = sockets.swap_remove(i);
+    socket.set_nonblocking(false).unwrap();
+    socket.connect(from).expect("connection failed");
+    Some(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn classification() {
+        assert_eq!(classify(&[40000, 40000, 40000]), None);
+        assert_eq!(classify(&[40000]), None);
+        assert_eq!(
+            classify(&[40000, 40001, 40002]),
+            Some(Sequential {
+                last: 40002,
+                delta: 1
+            })
+        );
+        assert_eq!(
+            classify(&[40000, 39998]),
+            Some(Sequential {
+                last: 39998,
+                delta: -2
+            })
+        );
+        let far = 40000 + SEQUENTIAL_RANGE as u16;
+        assert!(matches!(classify(&[40000, far]), Some(Sequential { .. })));
+        assert_eq!(classify(&[40000, far + 1]), Some(Random));
+        // the steps have to be the same
+        assert_eq!(classify(&[40000, 40001, 40003]), Some(Random));
+        assert_eq!(classify(&[40000, 40000, 40001]), Some(Random));
+    }
+
+    #[test]
+    fn predicted_ports() {
+        let ip = IpAddr::from([198, 51, 100, 1]);
+        let ports = |mapping| -> Vec<u16> {
+            predicted_candidates(mapping, ip)
+                .iter()
+                .map(|c| c.addr.port())
+                .collect()
+        };
+        let expected: Vec<u16> = (1..=SEQUENTIAL_RANGE as u16)
+            .map(|i| 1000 + 2 * i)
+            .collect();
+        assert_eq!(
+            ports(Sequential {
+                last: 1000,
+                delta: 2
+            }),
+            expected
+        );
+        assert_eq!(
+            ports(Sequential {
+                last: 30,
+                delta: -10
+            }),
+            [20, 10]
+        );
+        assert_eq!(
+            ports(Sequential {
+                last: 65530,
+                delta: 4
+            }),
+            [65534]
+        );
+        assert!(ports(Random).is_empty());
+        let candidates = predicted_candidates(
+            Sequential {
+                last: 1000,
+                delta: 1,
+            },
+            ip,
+        );
+        assert!(candidates.iter().all(|c| c.addr.ip() == ip));
+    }
+
+    #[test]
+    fn mapping_lines() {
+        for mapping in [
+            Sequential {
+                last: 4000,
+                delta: -3,
+            },
+            Random,
+        ] {
+            let line = mapping.encode();
+            let value = line.strip_prefix("n ").unwrap().trim_end();
+            assert_eq!(Mapping::decode(value), Some(mapping));
+        }
+        assert_eq!(Mapping::decode("s 4000"), None);
+    }
+
+    /// A helper with only its main port behind a NAT which counts ports: each new socket gets the
+    /// next port, the same socket keeps its own.
+    #[test]
+    fn measures_with_the_main_port_only() {
+        let helper = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let helper_addr = helper.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut mapped: HashMap<SocketAddr, u16> = HashMap::new();
+            let mut buf = [0u8; 576];
+            while let Ok((len, from)) = helper.recv_from(&mut buf) {
+                let next = 40000 + mapped.len() as u16;
+                let port = *mapped.entry(from).or_insert(next);
+                let public = SocketAddr::new(IpAddr::from([198, 51, 100, 1]), port);
+                if let Some((response, _)) = stun::answer(&buf[..len], public, None) {
+                    let _ = helper.send_to(&response, from);
+                }
+            }
+        });
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert_eq!(
+            measure(&socket, helper_addr, None),
+            Some(Sequential {
+                last: 40000 + FRESH_SOCKETS as u16,
+                delta: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,525 @@
+// The helper protocol, version 2, and the pairing reply of the legacy one.
+//
+// Every message is [MAGIC][version: u8][kind: u8][length: u16 BE][fields], where the fields are
+// [field: u8][length: u16 BE][value] each. Fields a side doesn't know are skipped, so new ones can
+// be added without a new version. The legacy protocol is a 200-byte zero-padded phrase in, and a
+// 200-byte zero-padded address out (followed by text lines for newer clients).
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+const MAGIC: &[u8] = b"qft2";
+pub const VERSION: u8 = 2;
+const HEADER: usize = MAGIC.len() + 4;
+
+/// Registrations are padded to this size, because the helper doesn't answer with more than it got
+/// and the pairing has to fit the partner's candidates.
+pub const REGISTER_SIZE: usize = 1200;
+/// Before registering, peers probe with a registration without phrase padded to this size. That is
+/// a byte short of a legacy phrase, so helpers which only know that protocol ignore it, while v2
+/// helpers refuse it with BAD_REQUEST and so show that they speak v2.
+pub const PROBE_SIZE: usize = 199;
+/// Longer phrases are refused.
+pub const MAX_PHRASE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// Peer to helper: the phrase and what the partner should know, sent again while waiting.
+    Register,
+    /// Helper to peer: the registration arrived, the partner didn't yet.
+    Waiting,
+    /// Helper to peer: the partner arrived.
+    Paired,
+    /// Helper to peer: the registration was refused or dropped.
+    Error,
+    /// Helper to peer: still waiting.
+    Keepalive,
+    /// Anyone to helper: asks for its counters, padded to REGISTER_SIZE. Helper to them: the
+    /// counters.
+    Status,
+    /// Peer to helper: asks for a nameplate for a code like `7-purple-sausage`, padded to
+    /// REGISTER_SIZE. Helper to peer: the nameplate.
+    Nameplate,
+}
+
+impl Kind {
+    fn id(self) -> u8 {
+        match self {
+            Kind::Register => 1,
+            Kind::Waiting => 2,
+            Kind::Paired => 3,
+            Kind::Error => 4,
+            Kind::Keepalive => 5,
+            Kind::Status => 6,
+            Kind::Nameplate => 7,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Kind> {
+        Some(match id {
+            1 => Kind::Register,
+            2 => Kind::Waiting,
+            3 => Kind::Paired,
+            4 => Kind::Error,
+            5 => Kind::Keepalive,
+            6 => Kind::Status,
+            7 => Kind::Nameplate,
+            _ => return None,
+        })
+    }
+}
+
+// fields
+pub const PADDING: u8 = 0;
+/// The phrase, any length up to MAX_PHRASE. (Register)
+pub const PHRASE: u8 = 1;
+/// A candidate list, see ice.rs. (Register, Paired)
+pub const CANDIDATES: u8 = 2;
+/// The partner's public address: [4 or 6][IP][port: u16 BE]. (Paired)
+pub const ADDRESS: u8 = 3;
+/// The helper's time and when to punch: [time: u64 BE][punch at: u64 BE]. (Paired)
+pub const SCHEDULE: u8 = 4;
+/// What went wrong, one of the codes below: [u16 BE]. (Error)
+pub const CODE: u8 = 5;
+/// The same for humans. (Error)
+pub const MESSAGE: u8 = 6;
+/// The address cookie, see guard.rs. (Register, Error)
+pub const COOKIE: u8 = 7;
+/// What the peer is going to do, see Role: [u8]. (Register)
+pub const ROLE: u8 = 8;
+/// The size of the file a sender is going to send: [u64 BE]. (Register, Paired)
+pub const FILE_SIZE: u8 = 9;
+/// The helper's counters in the Prometheus text format, see metrics.rs. (Status)
+pub const METRICS: u8 = 10;
+/// Proof that the peer knows one of a private helper's tokens, see guard::credential. (Register,
+/// Status)
+pub const CREDENTIAL: u8 = 11;
+/// A number nobody else waits with, see pairing::Nameplates: [u32 BE]. (Nameplate, and Register
+/// from the peer which asked for it, so the helper knows when it is free again)
+pub const NAMEPLATE: u8 = 12;
+
+// error codes
+/// The peer waited too long or the helper had to make room. Registering again is fine.
+pub const EXPIRED: u16 = 1;
+/// The helper wants the cookie it sent along to be sent back with the registration.
+pub const COOKIE_REQUIRED: u16 = 2;
+pub const TOO_MANY_WAITING: u16 = 3;
+pub const BAD_REQUEST: u16 = 4;
+pub const UNSUPPORTED_VERSION: u16 = 5;
+/// Someone with the same phrase is waiting, but doesn't want the same kind of partner.
+pub const ROLE_CONFLICT: u16 = 6;
+
+/// Peers are only paired with the role they need. Peers which don't say (older ones, and roles
+/// added later) are paired with anyone.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Sender,
+    Receiver,
+    Pipe,
+    TunnelListen,
+    TunnelConnect,
+}
+
+impl Role {
+    pub fn id(self) -> u8 {
+        match self {
+            Role::Sender => 1,
+            Role::Receiver => 2,
+            Role::Pipe => 3,
+            Role::TunnelListen => 4,
+            Role::TunnelConnect => 5,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Role> {
+        Some(match id {
+            1 => Role::Sender,
+            2 => Role::Receiver,
+            3 => Role::Pipe,
+            4 => Role::TunnelListen,
+            5 => Role::TunnelConnect,
+            _ => return None,
+        })
+    }
+
+    /// The role this one is paired with.
+    pub fn partner(self) -> Role {
+        match self {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+            Role::Pipe => Role::Pipe,
+            Role::TunnelListen => Role::TunnelConnect,
+            Role::TunnelConnect => Role::TunnelListen,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Sender => "sender",
+            Role::Receiver => "receiver",
+            Role::Pipe => "pipe",
+            Role::TunnelListen => "tunnel listening locally",
+            Role::TunnelConnect => "tunnel connecting to a target",
+        }
+    }
+}
+
+pub struct Message {
+    pub version: u8,
+    pub kind: Kind,
+    fields: Vec<(u8, Vec<u8>)>,
+}
+
+impl Message {
+    pub fn new(kind: Kind) -> Message {
+        Message {
+            version: VERSION,
+            kind,
+            fields: vec![],
+        }
+    }
+
+    pub fn with(mut self, field: u8, value: &[u8]) -> Message {
+        self.fields.push((field, value.to_vec()));
+        self
+    }
+
+    pub fn error(code: u16, message: &str) -> Message {
+        Message::new(Kind::Error)
+            .with(CODE, &code.to_be_bytes())
+            .with(MESSAGE, message.as_bytes())
+    }
+
+    pub fn get(&self, field: u8) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn code(&self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.get(CODE)?.try_into().ok()?))
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        Role::from_id(*self.get(ROLE)?.first()?)
+    }
+
+    pub fn file_size(&self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.get(FILE_SIZE)?.try_into().ok()?))
+    }
+
+    pub fn nameplate(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.get(NAMEPLATE)?.try_into().ok()?))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::from(MAGIC);
+        packet.push(self.version);
+        packet.push(self.kind.id());
+        packet.extend_from_slice(&[0, 0]);
+        for (field, value) in &self.fields {
+            packet.push(*field);
+            packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            packet.extend_from_slice(value);
+        }
+        let length = (packet.len() - HEADER) as u16;
+        packet[MAGIC.len() + 2..HEADER].copy_from_slice(&length.to_be_bytes());
+        packet
+    }
+
+    /// Like encode, but padded to at least `size` bytes.
+    pub fn encode_padded(&self, size: usize) -> Vec<u8> {
+        let mut packet = self.encode();
+        if packet.len() + 3 < size {
+            let padding = vec![0; size - packet.len() - 3];
+            packet = Message {
+                version: self.version,
+                kind: self.kind,
+                fields: self.fields.clone(),
+            }
+            .with(PADDING, &padding)
+            .encode();
+        }
+        packet
+    }
+
+    /// Returns None if `packet` isn't a message of this protocol. Messages of other versions are
+    /// returned (with whatever fields could be read), so they can be answered with an error.
+    pub fn decode(packet: &[u8]) -> Option<Message> {
+        if packet.len() < HEADER || !packet.starts_with(MAGIC) {
+            return None;
+        }
+        let version = packet[MAGIC.len()];
+        let length = u16::from_be_bytes([packet[MAGIC.len() + 2], packet[MAGIC.len() + 3]]);
+        if length as usize != packet.len() - HEADER {
+            return None;
+        }
+        // unknown kinds can't be answered in a useful way
+        let kind = Kind::from_id(packet[MAGIC.len() + 1])?;
+        let mut fields = vec![];
+        let mut rest = &packet[HEADER..];
+        while rest.len() >= 3 {
+            let length = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            if rest.len() < 3 + length {
+                return None;
+            }
+            fields.push((rest[0], rest[3..3 + length].to_vec()));
+            rest = &rest[3 + length..];
+        }
+        Some(Message {
+            version,
+            kind,
+            fields,
+        })
+    }
+}
+
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut value = vec![];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(4);
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value.push(6);
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    value.extend_from_slice(&addr.port().to_be_bytes());
+    value
+}
+
+fn decode_address(value: &[u8]) -> Option<SocketAddr> {
+    let ip = match (value.first()?, value.len()) {
+        (4, 7) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&value[1..5]).ok()?)),
+        (6, 19) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&value[1..17]).ok()?)),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([value[value.len() - 2], value[value.len() - 1]]);
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Which protocol a peer registered with, so it gets its answers the same way.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    Legacy,
+    V2,
+}
+
+/// What the helper tells a peer about its partner.
+pub struct Paired {
+    pub partner: SocketAddr,
+    /// The partner's candidate list, empty if it didn't send one.
+    pub candidates: Vec<u8>,
+    /// The helper's time and when to punch.
+    pub schedule: Option<(u64, u64)>,
+    /// The size of the file the partner is going to send, if it said so. Only in protocol v2.
+    pub file_size: Option<u64>,
+}
+
+impl Paired {
+    /// Encodes the pairing for a peer which sent `budget` bytes to register, the answer isn't
+    /// bigger. Candidates which don't fit are left out.
+    pub fn encode(&self, protocol: Protocol, budget: usize) -> Vec<u8> {
+        match protocol {
+            Protocol::Legacy => {
+                let mut reply = self.partner.to_string().into_bytes();
+                reply.resize(200, 0);
+                // only newer clients get the rest, older ones expect nothing after the address
+                if !self.candidates.is_empty() {
+                    if let Some((time, punch_at)) = self.schedule {
+                        reply.extend(format!("c {} {}\n", time, punch_at).into_bytes());
+                    }
+                    reply.extend(&self.candidates);
+                }
+                fit(&mut reply, 200, budget);
+                reply
+            }
+            Protocol::V2 => {
+                let mut message =
+                    Message::new(Kind::Paired).with(ADDRESS, &encode_address(self.partner));
+                if let Some((time, punch_at)) = self.schedule {
+                    let mut value = time.to_be_bytes().to_vec();
+                    value.extend_from_slice(&punch_at.to_be_bytes());
+                    message = message.with(SCHEDULE, &value);
+                }
+                if let Some(size) = self.file_size {
+                    message = message.with(FILE_SIZE, &size.to_be_bytes());
+                }
+                let without = message.encode().len() + 3;
+                let mut candidates = self.candidates.clone();
+                fit(&mut candidates, 0, budget.saturating_sub(without));
+                if !candidates.is_empty() {
+                    message = message.with(CANDIDATES, &candidates);
+                }
+                message.encode()
+            }
+        }
+    }
+
+    /// Reads a legacy pairing reply.
+    pub fn decode_legacy(reply: &[u8]) -> Option<Paired> {
+        let mut address = Vec::from(reply.get(..200)?);
+        address.retain(|b| *b != 0);
+        let partner = SocketAddr::from_str(&String::from_utf8_lossy(&address)).ok()?;
+        let text = String::from_utf8_lossy(&reply[200..]);
+        let schedule = text.lines().find_map(|line| {
+            let (time, punch_at) = line.strip_prefix("c ")?.split_once(' ')?;
+            Some((u64::from_str(time).ok()?, u64::from_str(punch_at).ok()?))
+        });
+        Some(Paired {
+            partner,
+            candidates: reply[200..].to_vec(),
+            schedule,
+            file_size: None,
+        })
+    }
+
+    pub fn decode(message: &Message) -> Option<Paired> {
+        let schedule = message.get(SCHEDULE).and_then(|value| {
+            Some((
+                u64::from_be_bytes(value.get(..8)?.try_into().ok()?),
+                u64::from_be_bytes(value.get(8..16)?.try_into().ok()?),
+            ))
+        });
+        Some(Paired {
+            partner: decode_address(message.get(ADDRESS)?)?,
+            candidates: message.get(CANDIDATES).unwrap_or(&[]).to_vec(),
+            schedule,
+            file_size: message.file_size(),
+        })
+    }
+}
+
+/// Cuts text down to `budget` bytes at the end of a line, but not below `keep` bytes.
+fn fit(text: &mut Vec<u8>, keep: usize, budget: usize) {
+    if text.len() <= budget {
+        return;
+    }
+    let end = text[..budget.max(keep)]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(keep, |i| (i + 1).max(keep));
+    text.truncate(end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let message = Message::new(Kind::Register)
+            .with(PHRASE, b"phrase")
+            .with(ROLE, &[Role::Sender.id()])
+            .with(FILE_SIZE, &300_000u64.to_be_bytes())
+            .with(NAMEPLATE, &7u32.to_be_bytes());
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.version, VERSION);
+        assert_eq!(decoded.kind, Kind::Register);
+        assert_eq!(decoded.get(PHRASE), Some(&b"phrase"[..]));
+        assert_eq!(decoded.role(), Some(Role::Sender));
+        assert_eq!(decoded.file_size(), Some(300_000));
+        assert_eq!(decoded.nameplate(), Some(7));
+        assert_eq!(decoded.get(COOKIE), None);
+
+        let error = Message::decode(&Message::error(EXPIRED, "too late").encode()).unwrap();
+        assert_eq!(error.kind, Kind::Error);
+        assert_eq!(error.code(), Some(EXPIRED));
+        assert_eq!(error.get(MESSAGE), Some(&b"too late"[..]));
+    }
+
+    #[test]
+    fn padding() {
+        let message = Message::new(Kind::Status);
+        let padded = message.encode_padded(REGISTER_SIZE);
+        assert_eq!(padded.len(), REGISTER_SIZE);
+        assert_eq!(Message::decode(&padded).unwrap().kind, Kind::Status);
+        // already big enough
+        let big = Message::new(Kind::Register).with(PHRASE, &[b'a'; REGISTER_SIZE]);
+        assert_eq!(big.encode_padded(REGISTER_SIZE), big.encode());
+    }
+
+    #[test]
+    fn decode_rejects_broken_messages() {
+        let packet = Message::new(Kind::Register)
+            .with(PHRASE, b"phrase")
+            .encode();
+        assert!(Message::decode(&packet[..packet.len() - 1]).is_none());
+        assert!(Message::decode(&packet[..HEADER - 1]).is_none());
+        let mut longer = packet.clone();
+        longer.push(0);
+        assert!(Message::decode(&longer).is_none());
+        let mut unknown_kind = packet.clone();
+        unknown_kind[MAGIC.len() + 1] = 200;
+        assert!(Message::decode(&unknown_kind).is_none());
+        let mut field_too_long = packet.clone();
+        field_too_long[HEADER + 2] += 1;
+        assert!(Message::decode(&field_too_long).is_none());
+        // other versions are read, so they can be told which one the helper speaks
+        let mut other_version = packet;
+        other_version[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            Message::decode(&other_version).unwrap().version,
+            VERSION + 1
+        );
+    }
+
+    #[test]
+    fn paired_round_trip() {
+        for partner in ["198.51.100.1:4000", "[2001:db8::1]:4000"] {
+            let paired = Paired {
+                partner: partner.parse().unwrap(),
+                candidates: b"host 192.0.2.1:4000\n".to_vec(),
+                schedule: Some((1000, 2000)),
+                file_size: Some(42),
+            };
+            let message = Message::decode(&paired.encode(Protocol::V2, REGISTER_SIZE)).unwrap();
+            let decoded = Paired::decode(&message).unwrap();
+            assert_eq!(decoded.partner, paired.partner);
+            assert_eq!(decoded.candidates, paired.candidates);
+            assert_eq!(decoded.schedule, paired.schedule);
+            assert_eq!(decoded.file_size, paired.file_size);
+        }
+    }
+
+    #[test]
+    fn paired_fits_the_budget() {
+        let paired = Paired {
+            partner: "198.51.100.1:4000".parse().unwrap(),
+            candidates: b"host 192.0.2.1:4000\nhost 192.0.2.2:4000\n".to_vec(),
+            schedule: None,
+            file_size: None,
+        };
+        let full = paired.encode(Protocol::V2, REGISTER_SIZE);
+        // room for the first candidate only
+        let encoded = paired.encode(Protocol::V2, full.len() - 20);
+        assert!(encoded.len() <= full.len() - 20);
+        let decoded = Paired::decode(&Message::decode(&encoded).unwrap()).unwrap();
+        assert_eq!(decoded.candidates, b"host 192.0.2.1:4000\n");
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        let paired = Paired {
+            partner: "198.51.100.1:4000".parse().unwrap(),
+            candidates: b"host 192.0.2.1:4000\n".to_vec(),
+            schedule: Some((1000, 2000)),
+            file_size: None,
+        };
+        let reply = paired.encode(Protocol::Legacy, 1024);
+        let decoded = Paired::decode_legacy(&reply).unwrap();
+        assert_eq!(decoded.partner, paired.partner);
+        assert_eq!(decoded.schedule, paired.schedule);
+        // without candidates, older clients get the address alone
+        let plain = Paired {
+            candidates: vec![],
+            ..paired
+        };
+        assert_eq!(plain.encode(Protocol::Legacy, 1024).len(), 200);
+    }
+}
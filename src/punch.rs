@@ -0,0 +1,289 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{canonical_addr, unix_millis};
+
+/// Asks the helper for the time: [TIME_MAGIC][our time: u64 BE][room for its time: 8]. It answers
+/// with [TIME_MAGIC][our time: u64 BE][its time: u64 BE], which is no bigger.
+const TIME_MAGIC: &[u8] = b"qft-time";
+const PUNCH_MAGIC: &[u8] = b"qft-punch";
+
+// kinds of punch packets: [PUNCH_MAGIC][kind: u8][phase: u8][tag: u64 BE]
+const PROBE: u8 = 0;
+const ACK: u8 = 1;
+const DONE: u8 = 2;
+
+// Probes go out right away first. If nothing got through after FAST_MS, they go out in bursts at
+// the moments the helper scheduled instead, so both NATs open at the same time.
+const FAST: u8 = 0;
+const TIMED: u8 = 1;
+const FAST_MS: u64 = 3000;
+const SLOT_MS: u64 = 500;
+const BURST: usize = 3;
+
+/// How far after the pairing the helper schedules the first burst.
+const PUNCH_DELAY_MS: u64 = 1000;
+
+/// When to punch, in the helper's time. The helper sends it along with the pairing, so both peers
+/// fire their bursts together even if their clocks disagree.
+pub struct Schedule {
+    /// The helper's clock minus ours, in milliseconds.
+    pub offset: i64,
+    /// The first burst, later ones follow every SLOT_MS.
+    pub punch_at: u64,
+}
+
+impl Schedule {
+    /// The helper side: its time and when the partners should punch.
+    pub fn plan() -> (u64, u64) {
+        let now = unix_millis();
+        (now, now + PUNCH_DELAY_MS)
+    }
+
+    /// The helper's `time` and `punch_at` from a pairing which arrived at `received`. The helper's
+    /// time is half a round trip old by then (a guess without one).
+    pub fn new(time: u64, punch_at: u64, round_trip: Option<u64>, received: u64) -> Schedule {
+        let one_way = round_trip.unwrap_or(0) / 2;
+        Schedule {
+            offset: (time + one_way) as i64 - received as i64,
+            punch_at,
+        }
+    }
+}
+
+/// The helper side: answers a time request, or returns None if the packet isn't one.
+pub fn answer_time(packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() != TIME_MAGIC.len() + 16 || !packet.starts_with(TIME_MAGIC) {
+        return None;
+    }
+    let mut response = packet.to_vec();
+    response[TIME_MAGIC.len() + 8..].copy_from_slice(&unix_millis().to_be_bytes());
+    Some(response)
+}
+
+/// Estimates how far the helper's clock is ahead of ours, in milliseconds, from the exchange with
+/// the shortest round trip (which was delayed the least on one of the ways). None if the helper
+/// doesn't answer, older ones don't know about this.
+pub fn clock_offset(socket: &UdpSocket, helper: SocketAddr) -> Option<i64> {
+    socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let mut best: Option<(u64, i64)> = None;
+    let mut buf = [0u8; 64];
+    for _ in 0..5 {
+        let mut request = Vec::from(TIME_MAGIC);
+        let sent = unix_millis();
+        request.extend_from_slice(&sent.to_be_bytes());
+        request.extend_from_slice(&[0; 8]);
+        let _ = socket.send_to(&request, helper);
+        while unix_millis() - sent < 200 {
+            let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            if canonical_addr(from) != canonical_addr(helper)
+                || len != TIME_MAGIC.len() + 16
+                || buf[..TIME_MAGIC.len() + 8] != request[..TIME_MAGIC.len() + 8]
+            {
+                continue;
+            }
+            let received = unix_millis();
+            let helper_time = u64::from_be_bytes(buf[len - 8..len].try_into().unwrap());
+            let rtt = received - sent;
+            // assume both ways took equally long
+            let offset = helper_time as i64 - (sent + rtt / 2) as i64;
+            match best {
+                Some((best_rtt, _)) if best_rtt <= rtt => (),
+                _ => best = Some((rtt, offset)),
+            }
+            break;
+        }
+    }
+    best.map(|(_, offset)| offset)
+}
+
+fn punch_packet(kind: u8, phase: u8, tag: u64) -> Vec<u8> {
+    let mut packet = Vec::from(PUNCH_MAGIC);
+    packet.push(kind);
+    packet.push(phase);
+    packet.extend_from_slice(&tag.to_be_bytes());
+    packet
+}
+
+/// Opens the way to `partner` by sending probes until the partner's probes get through as well.
+/// The peers agree on the phase: one moving on to timed bursts makes the other follow. Returns
+/// false if nothing got through before the timeout.
+pub fn punch(
+    socket: &UdpSocket,
+    partner: SocketAddr,
+    tag: u64,
+    schedule: &Schedule,
+    timeout: Duration,
+) -> bool {
+    socket
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let start = unix_millis();
+    let mut phase = FAST;
+    let mut last_send = 0;
+    let mut last_slot = 0;
+    // when the partner's last probe arrived
+    let mut last_probe: Option<u64> = None;
+    let mut buf = [0u8; 64];
+    while unix_millis() - start < timeout.as_millis() as u64 {
+        let now = unix_millis();
+        // The partner stops probing once it got our acknowledgement. Its confirmation can get
+        // lost, but then the probes stop coming anyway.
+        if last_probe.is_some_and(|last_probe| now - last_probe > 1500) {
+            return true;
+        }
+        if phase == FAST && now - start > FAST_MS {
+            eprintln!("Nothing got through yet, switching to timed holepunching...");
+            phase = TIMED;
+        }
+        match phase {
+            FAST if now - last_send >= 50 => {
+                last_send = now;
+                let _ = socket.send_to(&punch_packet(PROBE, phase, tag), partner);
+            }
+            TIMED => {
+                let helper_time = (now as i64 + schedule.offset) as u64;
+                // nothing before the first scheduled burst
+                let slot = (helper_time + SLOT_MS).saturating_sub(schedule.punch_at) / SLOT_MS;
+                if slot != last_slot {
+                    last_slot = slot;
+                    for _ in 0..BURST {
+                        let _ = socket.send_to(&punch_packet(PROBE, phase, tag), partner);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if canonical_addr(from) != canonical_addr(partner)
+            || len != PUNCH_MAGIC.len() + 10
+            || &buf[..PUNCH_MAGIC.len()] != PUNCH_MAGIC
+            || buf[PUNCH_MAGIC.len() + 2..len] != tag.to_be_bytes()
+        {
+            continue;
+        }
+        if buf[PUNCH_MAGIC.len() + 1] == TIMED && phase == FAST {
+            eprintln!("The partner switched to timed holepunching, following...");
+            phase = TIMED;
+        }
+        match buf[PUNCH_MAGIC.len()] {
+            PROBE => {
+                let _ = socket.send_to(&punch_packet(ACK, phase, tag), from);
+                last_probe = Some(unix_millis());
+            }
+            // it got our probe and we got its acknowledgement, so both ways are open
+            ACK => {
+                for _ in 0..5 {
+                    let _ = socket.send_to(&punch_packet(DONE, phase, tag), from);
+                }
+                return true;
+            }
+            DONE => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread};
+
+    use super::*;
+
+    fn socket() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap()
+    }
+
+    /// A helper whose clock is `ahead` milliseconds ahead of ours.
+    fn time_helper(ahead: u64) -> SocketAddr {
+        let helper = socket();
+        let addr = helper.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((len, from)) = helper.recv_from(&mut buf) {
+                let mut response = answer_time(&buf[..len]).unwrap();
+                let time = unix_millis() + ahead;
+                response[len - 8..].copy_from_slice(&time.to_be_bytes());
+                let _ = helper.send_to(&response, from);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn answers_time_requests_only() {
+        let mut request = Vec::from(TIME_MAGIC);
+        request.extend_from_slice(&[1; 16]);
+        let response = answer_time(&request).unwrap();
+        assert_eq!(response.len(), request.len());
+        assert_eq!(
+            response[..TIME_MAGIC.len() + 8],
+            request[..TIME_MAGIC.len() + 8]
+        );
+        assert!(answer_time(&request[..request.len() - 1]).is_none());
+        assert!(answer_time(&punch_packet(PROBE, FAST, 1)).is_none());
+    }
+
+    #[test]
+    fn measures_clock_offset() {
+        let offset = clock_offset(&socket(), time_helper(0)).unwrap();
+        assert!(offset.abs() < 50, "offset {}", offset);
+        let offset = clock_offset(&socket(), time_helper(10_000)).unwrap();
+        assert!((offset - 10_000).abs() < 50, "offset {}", offset);
+        // nobody answers
+        assert_eq!(
+            clock_offset(&socket(), socket().local_addr().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn schedule_counts_half_the_round_trip() {
+        let schedule = Schedule::new(1000, 2000, Some(100), 900);
+        assert_eq!(schedule.offset, 150);
+        assert_eq!(schedule.punch_at, 2000);
+        assert_eq!(Schedule::new(1000, 2000, None, 1100).offset, -100);
+    }
+
+    /// Both ends punching at once with their tags.
+    fn punch_pair(tags: [u64; 2], timeout: Duration) -> [bool; 2] {
+        let sockets = [socket(), socket()];
+        let addrs = [1, 0].map(|other| sockets[other].local_addr().unwrap());
+        let ends: Vec<_> = sockets
+            .into_iter()
+            .zip(addrs)
+            .zip(tags)
+            .map(|((socket, partner), tag)| {
+                thread::spawn(move || {
+                    let schedule = Schedule {
+                        offset: 0,
+                        punch_at: unix_millis(),
+                    };
+                    punch(&socket, partner, tag, &schedule, timeout)
+                })
+            })
+            .collect();
+        let results: Vec<bool> = ends.into_iter().map(|end| end.join().unwrap()).collect();
+        [results[0], results[1]]
+    }
+
+    #[test]
+    fn punches_with_the_same_tag() {
+        assert_eq!(punch_pair([7, 7], Duration::from_secs(5)), [true, true]);
+    }
+
+    #[test]
+    fn ignores_other_tags() {
+        assert_eq!(punch_pair([7, 8], Duration::from_secs(1)), [false, false]);
+    }
+}
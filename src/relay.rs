@@ -0,0 +1,375 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{canonical_addr, unix_millis};
+
+/// Asks the helper to relay, after the partner was paired. Sent again until it is answered.
+/// Padded to REQUEST_SIZE, so the answers aren't bigger.
+const REQUEST: &[u8] = b"qft-relay";
+const REQUEST_SIZE: usize = 16;
+const OK: &[u8] = b"qft-relay-ok";
+const OFF: &[u8] = b"qft-relay-off";
+
+/// How long pairs can still ask for a relay, and how long a silent relay session lives.
+const TIMEOUT_MS: u64 = 60_000;
+
+pub struct RelayLimits {
+    /// Bytes per second per session, both directions together.
+    pub rate: u64,
+    /// Bytes per session, both directions together.
+    pub quota: u64,
+}
+
+struct Session {
+    bytes: u64,
+    // token bucket for the rate limit, holding at most one second worth of bytes
+    tokens: u64,
+    last_refill: u64,
+    last_active: u64,
+}
+
+/// Whether `packet` asks for a relay.
+pub fn is_request(packet: &[u8]) -> bool {
+    packet.len() == REQUEST_SIZE && packet.starts_with(REQUEST)
+}
+
+/// The helper side. Only peers which were just paired by the helper, and which both ask for it,
+/// get relayed.
+pub struct Relay {
+    limits: Option<RelayLimits>,
+    // peers paired recently, with their partner and the time
+    pairs: HashMap<SocketAddr, (SocketAddr, u64)>,
+    waiting: HashSet<SocketAddr>,
+    partners: HashMap<SocketAddr, SocketAddr>,
+    sessions: HashMap<(SocketAddr, SocketAddr), Session>,
+    last_sweep: u64,
+}
+
+fn session_key(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    (a.min(b), a.max(b))
+}
+
+impl Relay {
+    /// None disables relaying, requests are then answered with a refusal.
+    pub fn new(limits: Option<RelayLimits>) -> Relay {
+        Relay {
+            limits,
+            pairs: HashMap::new(),
+            waiting: HashSet::new(),
+            partners: HashMap::new(),
+            sessions: HashMap::new(),
+            last_sweep: 0,
+        }
+    }
+
+    /// Remembers a pairing made by the helper, so the two may ask for a relay later.
+    pub fn paired(&mut self, a: SocketAddr, b: SocketAddr) {
+        if self.limits.is_some() {
+            self.pairs.insert(a, (b, unix_millis()));
+            self.pairs.insert(b, (a, unix_millis()));
+        }
+    }
+
+    /// Whether `addr` is being relayed right now. Those packets don't count towards rate limits.
+    pub fn relays(&self, addr: SocketAddr) -> bool {
+        self.partners.contains_key(&addr)
+    }
+
+    /// Handles relay requests and relayed packets. Returns false if the packet isn't for the
+    /// relay, so the helper handles it as usual.
+    pub fn handle(&mut self, socket: &UdpSocket, packet: &[u8], from: SocketAddr) -> bool {
+        self.sweep();
+        if is_request(packet) {
+            let Some(limits) = &self.limits else {
+                let _ = socket.send_to(OFF, from);
+                return true;
+            };
+            if self.partners.contains_key(&from) {
+                // our answer got lost
+                let _ = socket.send_to(OK, from);
+                return true;
+            }
+            let Some((partner, _)) = self.pairs.get(&from).copied() else {
+                return true;
+            };
+            self.waiting.insert(from);
+            if self.waiting.contains(&partner) {
+                self.waiting.remove(&from);
+                self.waiting.remove(&partner);
+                self.pairs.remove(&from);
+                self.pairs.remove(&partner);
+                self.partners.insert(from, partner);
+                self.partners.insert(partner, from);
+                self.sessions.insert(
+                    session_key(from, partner),
+                    Session {
+                        bytes: 0,
+                        tokens: limits.rate,
+                        last_refill: unix_millis(),
+                        last_active: unix_millis(),
+                    },
+                );
+                let _ = socket.send_to(OK, from);
+                let _ = socket.send_to(OK, partner);
+                println!(
+                    "Relaying between {} and {}.",
+                    canonical_addr(from),
+                    canonical_addr(partner)
+                );
+            }
+            return true;
+        }
+
+        let Some(partner) = self.partners.get(&from).copied() else {
+            return false;
+        };
+        let limits = self.limits.as_ref().unwrap();
+        let session = self.sessions.get_mut(&session_key(from, partner)).unwrap();
+        let now = unix_millis();
+        session.tokens =
+            (session.tokens + limits.rate * (now - session.last_refill) / 1000).min(limits.rate);
+        session.last_refill = now;
+        session.last_active = now;
+        let len = packet.len() as u64;
+        if session.tokens < len {
+            // too fast, the peers resend what gets dropped and slow down
+            return true;
+        }
+        session.tokens -= len;
+        session.bytes += len;
+        if session.bytes > limits.quota {
+            println!(
+                "Relay between {} and {} used up its quota.",
+                canonical_addr(from),
+                canonical_addr(partner)
+            );
+            self.end(from, partner);
+            return true;
+        }
+        let _ = socket.send_to(packet, partner);
+        true
+    }
+
+    fn end(&mut self, a: SocketAddr, b: SocketAddr) {
+        self.partners.remove(&a);
+        self.partners.remove(&b);
+        self.sessions.remove(&session_key(a, b));
+    }
+
+    fn sweep(&mut self) {
+        let now = unix_millis();
+        if now - self.last_sweep < 1000 {
+            return;
+        }
+        self.last_sweep = now;
+        self.pairs.retain(|_, (_, time)| now - *time < TIMEOUT_MS);
+        let pairs = &self.pairs;
+        self.waiting.retain(|addr| pairs.contains_key(addr));
+        let idle: Vec<(SocketAddr, SocketAddr)> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| now - s.last_active > TIMEOUT_MS)
+            .map(|(key, _)| *key)
+            .collect();
+        for (a, b) in idle {
+            println!(
+                "Relay between {} and {} ended.",
+                canonical_addr(a),
+                canonical_addr(b)
+            );
+            self.end(a, b);
+        }
+    }
+}
+
+/// The client side: asks the helper to relay between us and the partner it paired us with.
+/// Returns Ok once the relay is set up, or why it isn't.
+pub fn request(socket: &UdpSocket, helper: SocketAddr, timeout: Duration) -> Result<(), String> {
+    let start = unix_millis();
+    let mut last_send = 0;
+    let mut buf = [0u8; 64];
+    let mut request = Vec::from(REQUEST);
+    request.resize(REQUEST_SIZE, 0);
+    socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    while unix_millis() - start < timeout.as_millis() as u64 {
+        if unix_millis() - last_send >= 500 {
+            last_send = unix_millis();
+            let _ = socket.send_to(&request, helper);
+        }
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if from != helper {
+            continue;
+        }
+        if &buf[..len] == OK {
+            return Ok(());
+        }
+        if &buf[..len] == OFF {
+            return Err("the helper doesn't relay".to_owned());
+        }
+    }
+    Err("the helper or the partner didn't answer".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread};
+
+    use super::*;
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        socket
+    }
+
+    fn request_packet() -> Vec<u8> {
+        let mut request = Vec::from(REQUEST);
+        request.resize(REQUEST_SIZE, 0);
+        request
+    }
+
+    fn received(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).ok()?;
+        Some(buf[..len].to_vec())
+    }
+
+    /// A relay between two peers which both asked for it.
+    fn relaying(limits: RelayLimits) -> (Relay, UdpSocket, UdpSocket, UdpSocket) {
+        let (helper, a, b) = (socket(), socket(), socket());
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let mut relay = Relay::new(Some(limits));
+        relay.paired(a_addr, b_addr);
+        assert!(relay.handle(&helper, &request_packet(), a_addr));
+        assert!(relay.handle(&helper, &request_packet(), b_addr));
+        assert_eq!(received(&a).unwrap(), OK);
+        assert_eq!(received(&b).unwrap(), OK);
+        (relay, helper, a, b)
+    }
+
+    #[test]
+    fn relays_pairs_which_both_ask() {
+        let (helper, a, b, stranger) = (socket(), socket(), socket(), socket());
+        let [a_addr, b_addr, stranger_addr] = [&a, &b, &stranger].map(|s| s.local_addr().unwrap());
+        let mut relay = Relay::new(Some(RelayLimits {
+            rate: 1_000_000,
+            quota: 1_000_000,
+        }));
+        // nobody paired them
+        assert!(relay.handle(&helper, &request_packet(), stranger_addr));
+        assert_eq!(received(&stranger), None);
+
+        relay.paired(a_addr, b_addr);
+        assert!(relay.handle(&helper, &request_packet(), a_addr));
+        // waits for the partner
+        assert_eq!(received(&a), None);
+        assert!(!relay.relays(a_addr));
+        assert!(relay.handle(&helper, &request_packet(), b_addr));
+        assert_eq!(received(&a).unwrap(), OK);
+        assert_eq!(received(&b).unwrap(), OK);
+        assert!(relay.relays(a_addr) && relay.relays(b_addr));
+
+        assert!(relay.handle(&helper, b"to b", a_addr));
+        assert_eq!(received(&b).unwrap(), b"to b");
+        assert!(relay.handle(&helper, b"to a", b_addr));
+        assert_eq!(received(&a).unwrap(), b"to a");
+        // other packets are for the helper itself
+        assert!(!relay.handle(&helper, b"phrase", stranger_addr));
+    }
+
+    #[test]
+    fn enforces_rate() {
+        let (mut relay, helper, a, b) = relaying(RelayLimits {
+            rate: 1000,
+            quota: 1_000_000,
+        });
+        let a_addr = a.local_addr().unwrap();
+        for _ in 0..3 {
+            assert!(relay.handle(&helper, &[1; 400], a_addr));
+        }
+        // a second's worth of bytes fits two of them
+        assert!(received(&b).is_some());
+        assert!(received(&b).is_some());
+        assert_eq!(received(&b), None);
+        // the bucket refills over time
+        thread::sleep(Duration::from_millis(500));
+        assert!(relay.handle(&helper, &[1; 400], a_addr));
+        assert!(received(&b).is_some());
+        assert!(relay.relays(a_addr));
+    }
+
+    #[test]
+    fn enforces_quota() {
+        let (mut relay, helper, a, b) = relaying(RelayLimits {
+            rate: 1_000_000,
+            quota: 1000,
+        });
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        assert!(relay.handle(&helper, &[1; 400], a_addr));
+        assert!(relay.handle(&helper, &[2; 400], b_addr));
+        assert!(received(&b).is_some());
+        assert!(received(&a).is_some());
+        // both directions count, this one goes over
+        assert!(relay.handle(&helper, &[3; 400], a_addr));
+        assert_eq!(received(&b), None);
+        assert!(!relay.relays(a_addr) && !relay.relays(b_addr));
+        assert!(!relay.handle(&helper, &[4; 400], a_addr));
+    }
+
+    #[test]
+    fn request_errors() {
+        // a helper without relaying
+        let helper = socket();
+        let helper_addr = helper.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut relay = Relay::new(None);
+            let mut buf = [0u8; 64];
+            while let Ok((len, from)) = helper.recv_from(&mut buf) {
+                relay.handle(&helper, &buf[..len], from);
+            }
+        });
+        assert_eq!(
+            request(&socket(), helper_addr, Duration::from_secs(2)),
+            Err("the helper doesn't relay".to_owned())
+        );
+        // nobody answers
+        let silent = socket().local_addr().unwrap();
+        assert_eq!(
+            request(&socket(), silent, Duration::from_millis(600)),
+            Err("the helper or the partner didn't answer".to_owned())
+        );
+    }
+
+    #[test]
+    fn request_succeeds() {
+        let (helper, b) = (socket(), socket());
+        let helper_addr = helper.local_addr().unwrap();
+        let client = socket();
+        let (client_addr, b_addr) = (client.local_addr().unwrap(), b.local_addr().unwrap());
+        let mut relay = Relay::new(Some(RelayLimits {
+            rate: 1_000_000,
+            quota: 1_000_000,
+        }));
+        relay.paired(client_addr, b_addr);
+        assert!(relay.handle(&helper, &request_packet(), b_addr));
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((len, from)) = helper.recv_from(&mut buf) {
+                relay.handle(&helper, &buf[..len], from);
+            }
+        });
+        assert_eq!(
+            request(&client, helper_addr, Duration::from_secs(2)),
+            Ok(())
+        );
+    }
+}
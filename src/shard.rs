@@ -0,0 +1,36 @@
+// Splits state which all helper threads use into parts with their own lock, picked by a key, so
+// threads working on different keys don't wait for each other.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+    sync::{Mutex, MutexGuard},
+};
+
+pub struct Shards<T> {
+    shards: Vec<Mutex<T>>,
+    // random, so nobody can pick keys which all end up in the same shard
+    hasher: RandomState,
+}
+
+impl<T> Shards<T> {
+    pub fn new(count: usize, mut make: impl FnMut() -> T) -> Shards<T> {
+        Shards {
+            shards: (0..count.max(1)).map(|_| Mutex::new(make())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Locks the shard `key` belongs to.
+    pub fn get(&self, key: &impl Hash) -> MutexGuard<'_, T> {
+        let i = self.hasher.hash_one(key) as usize % self.shards.len();
+        self.shards[i].lock().unwrap()
+    }
+
+    /// Locks each shard in turn.
+    pub fn each(&self, mut f: impl FnMut(&mut T)) {
+        for shard in &self.shards {
+            f(&mut shard.lock().unwrap());
+        }
+    }
+}
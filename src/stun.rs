@@ -0,0 +1,394 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use crate::{nonce, print_args, unix_millis};
+
+// RFC 8489 (and 5389, which it replaces)
+const MAGIC_COOKIE: u32 = 0x2112A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const CHANGE_REQUEST: u16 = 0x0003;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const SOFTWARE: u16 = 0x8022;
+// Not standardized: the helper's second port, if it has one. RFC 5780's OTHER-ADDRESS would need
+// the helper's public IP, which it doesn't know. Comprehension-optional, so others ignore it.
+const OTHER_PORT: u16 = 0x8f70;
+// in CHANGE-REQUEST
+const CHANGE_PORT_FLAG: u32 = 0x2;
+// the size of our requests, enough for the biggest answer of a helper
+const REQUEST_SIZE: usize = 64;
+
+pub const DEFAULT_PORT: u16 = 3478;
+
+/// Resolves a STUN server address, the port may be left out.
+pub fn resolve(server: &str) -> Result<SocketAddr, Error> {
+    let mut addrs = match server.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => (server, DEFAULT_PORT).to_socket_addrs()?,
+    };
+    addrs
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "STUN server has no address"))
+}
+
+fn header(kind: u16, length: u16, transaction: &[u8; 12]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(20 + length as usize);
+    packet.extend_from_slice(&kind.to_be_bytes());
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    packet.extend_from_slice(transaction);
+    packet
+}
+
+pub struct BindingResponse {
+    /// The address the server saw our request come from.
+    pub mapped: SocketAddr,
+    /// Where the response came from, differs from the server address if a port change was asked
+    /// for.
+    pub from: SocketAddr,
+    /// The server's second port, if it is a qft helper with one.
+    pub other_port: Option<u16>,
+}
+
+/// Asks a STUN server which address our packets come from.
+pub fn binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<SocketAddr, Error> {
+    request(socket, server, false, timeout).map(|r| r.mapped)
+}
+
+/// A Binding request, and the transaction id its response will have. With `change_port`, the
+/// server is asked to answer from its other port (RFC 5780), which only works if it has one.
+pub fn binding_request(change_port: bool) -> (Vec<u8>, [u8; 12]) {
+    let mut transaction = [0u8; 12];
+    transaction[..8].copy_from_slice(&nonce().to_be_bytes());
+    transaction[8..].copy_from_slice(&(unix_millis() as u32).to_be_bytes());
+    let mut request = header(BINDING_REQUEST, 0, &transaction);
+    if change_port {
+        push_attribute(
+            &mut request,
+            CHANGE_REQUEST,
+            &CHANGE_PORT_FLAG.to_be_bytes(),
+        );
+    }
+    // The helper doesn't answer with more than it got, so nobody can use it to flood someone with
+    // forged requests. This makes room for everything it answers, other servers ignore it.
+    let room = REQUEST_SIZE - request.len() - 4;
+    push_attribute(
+        &mut request,
+        SOFTWARE,
+        format!("{:1$}", "qft", room).as_bytes(),
+    );
+    let length = (request.len() - 20) as u16;
+    request[2..4].copy_from_slice(&length.to_be_bytes());
+    (request, transaction)
+}
+
+/// Returns None if `packet` isn't the response to the request with `transaction`, and the mapped
+/// address and OTHER_PORT otherwise.
+pub fn parse_response(
+    packet: &[u8],
+    transaction: &[u8; 12],
+) -> Option<Result<(SocketAddr, Option<u16>), Error>> {
+    if packet.len() < 20 || packet[8..20] != *transaction {
+        return None;
+    }
+    if u16::from_be_bytes([packet[0], packet[1]]) != BINDING_RESPONSE {
+        return Some(Err(Error::other("STUN server refused request")));
+    }
+    let (mapped, other_port) = parse_attributes(packet);
+    Some(match mapped {
+        Some(mapped) => Ok((mapped, other_port)),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "STUN response has no address",
+        )),
+    })
+}
+
+/// Sends a Binding request, retransmitting like RFC 8489 suggests (doubling the wait each time)
+/// until `timeout` runs out.
+pub fn request(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change_port: bool,
+    timeout: Duration,
+) -> Result<BindingResponse, Error> {
+    let (request, transaction) = binding_request(change_port);
+    let start = unix_millis();
+    let mut wait = 500;
+    let mut buf = [0u8; 576];
+    while unix_millis() - start < timeout.as_millis() as u64 {
+        socket.send_to(&request, server)?;
+        let sent = unix_millis();
+        while unix_millis() - sent < wait {
+            socket.set_read_timeout(Some(Duration::from_millis(
+                wait.saturating_sub(unix_millis() - sent).max(1),
+            )))?;
+            let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            if from.ip() != server.ip() {
+                continue;
+            }
+            let Some(response) = parse_response(&buf[..len], &transaction) else {
+                continue;
+            };
+            let (mapped, other_port) = response?;
+            return Ok(BindingResponse {
+                mapped,
+                from,
+                other_port,
+            });
+        }
+        wait = (wait * 2).min(3200);
+    }
+    Err(Error::new(ErrorKind::TimedOut, "STUN server didn't answer"))
+}
+
+/// Returns the (XOR-)MAPPED-ADDRESS and OTHER_PORT of a response, if present.
+fn parse_attributes(packet: &[u8]) -> (Option<SocketAddr>, Option<u16>) {
+    let mut xor_mapped = None;
+    let mut mapped = None;
+    let mut other_port = None;
+    for (kind, value) in attributes(packet) {
+        match kind {
+            XOR_MAPPED_ADDRESS => xor_mapped = decode_address(value, Some(&packet[4..20])),
+            MAPPED_ADDRESS => mapped = decode_address(value, None),
+            OTHER_PORT if value.len() == 2 => {
+                other_port = Some(u16::from_be_bytes([value[0], value[1]]))
+            }
+            _ => (),
+        }
+    }
+    (xor_mapped.or(mapped), other_port)
+}
+
+fn attributes(packet: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut list = vec![];
+    let mut rest = &packet[20..];
+    while rest.len() >= 4 {
+        let kind = u16::from_be_bytes([rest[0], rest[1]]);
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        let Some(value) = rest.get(4..4 + len) else {
+            break;
+        };
+        list.push((kind, value));
+        // attributes are padded to 4 bytes
+        rest = rest.get((4 + len + 3) & !3..).unwrap_or(&[]);
+    }
+    list
+}
+
+/// Decodes a (XOR-)MAPPED-ADDRESS value. `xor` is the magic cookie and transaction id, if the
+/// address is XOR'd with them.
+fn decode_address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    if value.len() < 8 {
+        return None;
+    }
+    let mask = xor.unwrap_or(&[0; 16]);
+    let port = u16::from_be_bytes([value[2] ^ mask[0], value[3] ^ mask[1]]);
+    match value[1] {
+        1 => {
+            let mut ip = [0u8; 4];
+            for (i, b) in ip.iter_mut().enumerate() {
+                *b = value[4 + i] ^ mask[i];
+            }
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+        }
+        2 if value.len() >= 20 => {
+            let mut ip = [0u8; 16];
+            for (i, b) in ip.iter_mut().enumerate() {
+                *b = value[4 + i] ^ mask[i];
+            }
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        }
+        _ => None,
+    }
+}
+
+fn encode_address(addr: SocketAddr, xor: Option<&[u8]>) -> Vec<u8> {
+    let mask = xor.unwrap_or(&[0; 16]);
+    let mut value = vec![0, if addr.is_ipv4() { 1 } else { 2 }];
+    let port = addr.port().to_be_bytes();
+    value.push(port[0] ^ mask[0]);
+    value.push(port[1] ^ mask[1]);
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    for (i, b) in ip.iter().enumerate() {
+        value.push(b ^ mask[i]);
+    }
+    value
+}
+
+fn push_attribute(packet: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    packet.extend_from_slice(&kind.to_be_bytes());
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value);
+    while packet.len() & 3 != 0 {
+        packet.push(0);
+    }
+}
+
+/// If `request` is a STUN Binding request, returns the success response telling `from` its
+/// address, and whether it should be sent from the other port. Requests without the magic cookie
+/// (RFC 3489 clients) get a plain MAPPED-ADDRESS. Helpers send the response with guard::reply, so
+/// requests have to be padded to its size, like qft's own are.
+pub fn answer(
+    request: &[u8],
+    from: SocketAddr,
+    other_port: Option<u16>,
+) -> Option<(Vec<u8>, bool)> {
+    if request.len() < 20
+        || request[0] & 0xc0 != 0
+        || u16::from_be_bytes([request[0], request[1]]) != BINDING_REQUEST
+        || u16::from_be_bytes([request[2], request[3]]) as usize != request.len() - 20
+    {
+        return None;
+    }
+    let change_port = attributes(request).iter().any(|(kind, value)| {
+        *kind == CHANGE_REQUEST
+            && value.len() == 4
+            && u32::from_be_bytes((*value).try_into().unwrap()) & CHANGE_PORT_FLAG != 0
+    });
+    let modern = request[4..8] == MAGIC_COOKIE.to_be_bytes();
+    let mut response = Vec::from(&request[..20]);
+    response[0..2].copy_from_slice(&BINDING_RESPONSE.to_be_bytes());
+    if modern {
+        push_attribute(
+            &mut response,
+            XOR_MAPPED_ADDRESS,
+            &encode_address(from, Some(&request[4..20])),
+        );
+    } else {
+        push_attribute(&mut response, MAPPED_ADDRESS, &encode_address(from, None));
+    }
+    if let Some(port) = other_port {
+        push_attribute(&mut response, OTHER_PORT, &port.to_be_bytes());
+    }
+    if response.len() + 16 <= request.len() {
+        push_attribute(&mut response, SOFTWARE, b"qft helper");
+    }
+    let length = (response.len() - 20) as u16;
+    response[2..4].copy_from_slice(&length.to_be_bytes());
+    Some((response, change_port && other_port.is_some()))
+}
+
+/// Shows our public address as seen by a STUN server.
+pub fn stun(args: &[String]) {
+    let server = resolve(args.get(2).unwrap_or_else(|| {
+        print_args(args);
+        panic!("unreachable")
+    }))
+    .expect("unable to resolve STUN server");
+    let bind_addr: SocketAddr = if server.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).expect("unable to create socket");
+    match binding(&socket, server, Duration::from_secs(10)) {
+        Ok(addr) => println!(
+            "Your public address is {} (local port {}).",
+            addr,
+            socket.local_addr().unwrap().port()
+        ),
+        Err(e) => println!("STUN request to {} failed: {}", server, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from() -> SocketAddr {
+        "203.0.113.7:40000".parse().unwrap()
+    }
+
+    #[test]
+    fn unpadded_requests_get_bigger_answers() {
+        // a plain RFC 8489 Binding request: helpers don't send its answer, which is bigger
+        let transaction = [7u8; 12];
+        let request = header(BINDING_REQUEST, 0, &transaction);
+        assert_eq!(request.len(), 20);
+        let (response, change_port) = answer(&request, from(), Some(4301)).unwrap();
+        assert!(!change_port);
+        assert!(response.len() > request.len());
+        let (mapped, other_port) = parse_response(&response, &transaction).unwrap().unwrap();
+        assert_eq!(mapped, from());
+        assert_eq!(other_port, Some(4301));
+    }
+
+    #[test]
+    fn answers_rfc3489_request() {
+        let mut request = header(BINDING_REQUEST, 0, &[1u8; 12]);
+        request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        let (response, _) = answer(&request, from(), None).unwrap();
+        let attributes = attributes(&response);
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes[0].0, MAPPED_ADDRESS);
+        assert_eq!(decode_address(attributes[0].1, None), Some(from()));
+    }
+
+    #[test]
+    fn answers_own_requests() {
+        let (request, transaction) = binding_request(false);
+        assert_eq!(request.len(), REQUEST_SIZE);
+        let (response, change_port) = answer(&request, from(), Some(4301)).unwrap();
+        assert!(!change_port);
+        assert!(response.len() <= request.len());
+        assert!(attributes(&response)
+            .iter()
+            .any(|(kind, _)| *kind == SOFTWARE));
+        let (mapped, other_port) = parse_response(&response, &transaction).unwrap().unwrap();
+        assert_eq!(mapped, from());
+        assert_eq!(other_port, Some(4301));
+
+        let v6: SocketAddr = "[2001:db8::7]:40000".parse().unwrap();
+        let (response, _) = answer(&request, v6, None).unwrap();
+        let (mapped, other_port) = parse_response(&response, &transaction).unwrap().unwrap();
+        assert_eq!(mapped, v6);
+        assert_eq!(other_port, None);
+    }
+
+    #[test]
+    fn change_port_needs_another_port() {
+        let (request, _) = binding_request(true);
+        assert_eq!(request.len(), REQUEST_SIZE);
+        assert!(answer(&request, from(), Some(4301)).unwrap().1);
+        assert!(!answer(&request, from(), None).unwrap().1);
+    }
+
+    #[test]
+    fn responses_to_others_and_errors() {
+        let (request, transaction) = binding_request(false);
+        let (response, _) = answer(&request, from(), None).unwrap();
+        assert!(parse_response(&response, &[0; 12]).is_none());
+        assert!(parse_response(&response[..19], &transaction).is_none());
+        let mut error = response.clone();
+        // Binding error response
+        error[0..2].copy_from_slice(&0x0111u16.to_be_bytes());
+        assert!(parse_response(&error, &transaction).unwrap().is_err());
+        let empty = header(BINDING_RESPONSE, 0, &transaction);
+        assert!(parse_response(&empty, &transaction).unwrap().is_err());
+    }
+
+    #[test]
+    fn ignores_other_packets() {
+        let (mut request, _) = binding_request(false);
+        assert!(answer(&request[..19], from(), None).is_none());
+        // wrong length
+        assert!(answer(&request[..request.len() - 4], from(), None).is_none());
+        request[0..2].copy_from_slice(&BINDING_RESPONSE.to_be_bytes());
+        assert!(answer(&request, from(), None).is_none());
+        assert!(answer(b"qft-cookie and some more bytes", from(), None).is_none());
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    process,
+    sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    holepunch,
+    mux::{Mux, MuxEvent},
+    protocol::Role,
+    HolepunchError, SafeReadWrite,
+};
+
+enum Event {
+    Accepted(TcpStream),
+    Connected(u32, TcpStream),
+    ConnectFailed(u32),
+    // a writer thread put this many bytes into its TCP connection
+    Written(u32, usize),
+}
+use Event::*;
+
+struct Connection {
+    // chunks read from the TCP connection, None once it hit EOF
+    reader: Option<Receiver<Vec<u8>>>,
+    // data for the TCP connection, None until it is connected or after the partner closed
+    writer: Option<Sender<Vec<u8>>>,
+    // data that arrived before the outgoing TCP connection was established
+    pending: Vec<Vec<u8>>,
+    remote_closed: bool,
+}
+
+/// Forwards TCP connections over the holepunched connection, like `ssh -L`. One end listens on a
+/// local port, the other end connects every accepted connection to the target. Swapping which end
+/// listens gives the `ssh -R` behavior. Each TCP connection is its own mux stream.
+pub fn tunnel(args: &[String]) -> Result<(), HolepunchError> {
+    let (listening, target, dly, br) = options(args).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(1)
+    });
+
+    let (tx, rx) = mpsc::channel::<Event>();
+    if listening {
+        // checked by options
+        let port = target.parse::<u16>().unwrap();
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| {
+            eprintln!("Unable to listen on port {}: {}", port, e);
+            process::exit(1)
+        });
+        accept(listener, tx.clone());
+    }
+
+    let role = match listening {
+        true => Role::TunnelListen,
+        false => Role::TunnelConnect,
+    };
+    let connection = holepunch(args, role, None)?;
+    let mux = Mux::new(SafeReadWrite::new(connection), br as usize, dly);
+    if listening {
+        eprintln!("Forwarding 127.0.0.1:{} to the partner.", target);
+    } else {
+        eprintln!("Forwarding connections from the partner to {}.", target);
+    }
+    forward(mux, listening, &target, tx, rx);
+    Ok(())
+}
+
+/// Whether we listen, the local port or target, send delay and bitrate.
+fn options(args: &[String]) -> Result<(bool, String, u64, u32), String> {
+    let usage = "tunnel needs listen <local-port> or connect <target-host>:<target-port>";
+    let listening = match args.get(4).map(String::as_str) {
+        Some("listen") => true,
+        Some("connect") => false,
+        _ => return Err(usage.to_string()),
+    };
+    let target = args.get(5).ok_or(usage)?.clone();
+    if listening && target.parse::<u16>().is_err() {
+        return Err(format!("invalid port {}: must be integer", target));
+    }
+    let dly = args
+        .get(6)
+        .map(|s| s.parse::<u64>())
+        .unwrap_or(Ok(500))
+        .map_err(|_| "bad delay operand")?;
+    let br = args
+        .get(7)
+        .map(|s| s.parse::<u32>())
+        .unwrap_or(Ok(1024))
+        .map_err(|_| "bad bitrate argument")?;
+    Ok((listening, target, dly, br))
+}
+
+/// Hands every connection accepted by `listener` to the forwarding loop.
+fn accept(listener: TcpListener, tx: Sender<Event>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            if tx.send(Accepted(stream)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Runs the tunnel until the partner ends it. `tx` gives the events of the forwarding threads to
+/// `rx`.
+fn forward(mut mux: Mux, listening: bool, target: &str, tx: Sender<Event>, rx: Receiver<Event>) {
+    let mut connections: HashMap<u32, Connection> = HashMap::new();
+    loop {
+        loop {
+            let event = match rx.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            };
+            match event {
+                Accepted(stream) => {
+                    let id = mux.open();
+                    eprintln!("Connection {} opened.", id);
+                    connections.insert(
+                        id,
+                        Connection {
+                            reader: Some(spawn_reader(&stream, mux.max_payload())),
+                            writer: Some(spawn_writer(id, stream, tx.clone())),
+                            pending: vec![],
+                            remote_closed: false,
+                        },
+                    );
+                }
+                Connected(id, stream) => {
+                    let Some(c) = connections.get_mut(&id) else {
+                        continue;
+                    };
+                    c.reader = Some(spawn_reader(&stream, mux.max_payload()));
+                    let writer = spawn_writer(id, stream, tx.clone());
+                    for data in c.pending.drain(..) {
+                        let _ = writer.send(data);
+                    }
+                    // if the partner already closed, dropping the writer shuts the connection down
+                    if !c.remote_closed {
+                        c.writer = Some(writer);
+                    }
+                }
+                ConnectFailed(id) => {
+                    eprintln!("Connection {}: unable to connect to {}.", id, target);
+                    connections.remove(&id);
+                    mux.close(id);
+                }
+                Written(id, amount) => mux.consumed(id, amount),
+            }
+        }
+
+        // one chunk per connection and round, so every connection gets its fair share
+        let mut sent_any = false;
+        let mut finished = vec![];
+        for (id, c) in connections.iter_mut() {
+            let Some(reader) = c.reader.as_ref() else {
+                continue;
+            };
+            if mux.send_window(*id) < mux.max_payload() {
+                continue;
+            }
+            match reader.try_recv() {
+                Ok(data) => {
+                    mux.write(*id, &data);
+                    sent_any = true;
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => {
+                    c.reader = None;
+                    mux.close(*id);
+                    if c.remote_closed {
+                        finished.push(*id);
+                    }
+                }
+            }
+        }
+        for id in finished {
+            connections.remove(&id);
+            eprintln!("Connection {} closed.", id);
+        }
+
+        let timeout = Duration::from_millis(if sent_any { 1 } else { 10 });
+        match mux.poll(timeout) {
+            Some(MuxEvent::Opened(id)) if !listening => {
+                eprintln!("Connection {} opened.", id);
+                connections.insert(
+                    id,
+                    Connection {
+                        reader: None,
+                        writer: None,
+                        pending: vec![],
+                        remote_closed: false,
+                    },
+                );
+                let target = target.to_string();
+                let tx = tx.clone();
+                thread::spawn(move || match TcpStream::connect(target.as_str()) {
+                    Ok(stream) => {
+                        let _ = tx.send(Connected(id, stream));
+                    }
+                    Err(_) => {
+                        let _ = tx.send(ConnectFailed(id));
+                    }
+                });
+            }
+            Some(MuxEvent::Data(id, data)) => {
+                if let Some(c) = connections.get_mut(&id) {
+                    match c.writer.as_ref() {
+                        Some(writer) => {
+                            let _ = writer.send(data);
+                        }
+                        None => c.pending.push(data),
+                    }
+                }
+            }
+            Some(MuxEvent::Closed(id)) => {
+                if let Some(c) = connections.get_mut(&id) {
+                    c.remote_closed = true;
+                    c.writer = None;
+                    if c.reader.is_none() && c.pending.is_empty() {
+                        // also covers connections which never got connected
+                        mux.close(id);
+                        connections.remove(&id);
+                        eprintln!("Connection {} closed.", id);
+                    }
+                }
+            }
+            Some(MuxEvent::End) => {
+                eprintln!("Partner closed the tunnel.");
+                return;
+            }
+            Some(MuxEvent::Opened(id)) => mux.close(id),
+            None => (),
+        }
+    }
+}
+
+fn spawn_reader(stream: &TcpStream, size: usize) -> Receiver<Vec<u8>> {
+    let mut stream = stream.try_clone().expect("unable to clone tcp stream");
+    // a few chunks of buffer, after that the reader waits for the mux to catch up
+    let (tx, rx): (SyncSender<Vec<u8>>, _) = mpsc::sync_channel(4);
+    thread::spawn(move || {
+        let mut buf = vec![0u8; size];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(read) => {
+                    if tx.send(buf[..read].to_vec()).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn spawn_writer(id: u32, mut stream: TcpStream, events: Sender<Event>) -> Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for data in rx {
+            if stream.write_all(&data).is_err() {
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+            if events.send(Written(id, data.len())).is_err() {
+                return;
+            }
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use super::*;
+
+    fn args(rest: &[&str]) -> Vec<String> {
+        ["qft", "tunnel", "helper:4200", "phrase"]
+            .iter()
+            .chain(rest)
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn bad_options() {
+        assert!(options(&args(&[])).is_err());
+        assert!(options(&args(&["forward", "22"])).is_err());
+        assert!(options(&args(&["listen"])).is_err());
+        assert!(options(&args(&["listen", "ssh"])).is_err());
+        assert!(options(&args(&["listen", "2222", "fast"])).is_err());
+        assert!(options(&args(&["connect", "host:22", "500", "-1"])).is_err());
+        assert_eq!(
+            options(&args(&["connect", "host:22"])),
+            Ok((false, "host:22".to_string(), 500, 1024))
+        );
+    }
+
+    #[test]
+    fn echoes_over_loopback() {
+        // the target, which sends everything back
+        let echo = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = echo.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in echo.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = stream.try_clone().unwrap();
+                    std::io::copy(&mut reader, &mut stream).unwrap();
+                });
+            }
+        });
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let local = listener.local_addr().unwrap();
+        let a = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let b = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        thread::spawn(move || {
+            let (tx, rx) = mpsc::channel();
+            accept(listener, tx.clone());
+            let mux = Mux::new(SafeReadWrite::new(a), 1024, 0);
+            forward(mux, true, &local.port().to_string(), tx, rx);
+        });
+        thread::spawn(move || {
+            let (tx, rx) = mpsc::channel();
+            let mux = Mux::new(SafeReadWrite::new(b), 1024, 0);
+            forward(mux, false, &target, tx, rx);
+        });
+
+        // two connections at once, each gets its own data back
+        let mut streams = [1u8, 2].map(|seed| {
+            let stream = TcpStream::connect(local).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            (stream, vec![seed; 5000])
+        });
+        for (stream, data) in &mut streams {
+            stream.write_all(data).unwrap();
+        }
+        for (stream, data) in &mut streams {
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut got = vec![];
+            stream.read_to_end(&mut got).unwrap();
+            assert!(got == *data, "got {} bytes", got.len());
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// Codes like `7-purple-sausage`: a number the helper hands out, so nobody else waits with the same
+// code, and two random words, so others don't connect by accident. The words are easy to say and to
+// spell, but only 16 bits: someone trying codes on purpose finds the right one quickly.
+
+use std::{
+    env,
+    net::{SocketAddr, UdpSocket},
+};
+
+use crate::{
+    guard, os_random,
+    protocol::{self, Kind, Message},
+    unix_millis,
+};
+
+const ADJECTIVES: [&str; 256] = [
+    "able", "acid", "aged", "airy", "alert", "alive", "amber", "ample", "angry", "apt", "arctic",
+    "ashen", "avid", "awake", "azure", "baggy", "bald", "balmy", "basic", "beige", "best", "big",
+    "bitter", "black", "bland", "blank", "blue", "blunt", "bold", "bony", "bossy", "brave",
+    "brief", "bright", "brisk", "broad", "brown", "bumpy", "busy", "calm", "candid", "chief",
+    "chilly", "civil", "clean", "clear", "clever", "close", "cloudy", "cold", "comic", "cool",
+    "coral", "cosmic", "cozy", "crisp", "cuddly", "curly", "cute", "daily", "damp", "dapper",
+    "dark", "dear", "deep", "dense", "dizzy", "dry", "dusty", "eager", "early", "earthy", "easy",
+    "elder", "empty", "epic", "equal", "even", "exact", "extra", "faint", "fair", "fancy", "far",
+    "fast", "fierce", "fine", "firm", "fit", "flat", "fluffy", "fond", "free", "fresh", "frosty",
+    "full", "funny", "fuzzy", "gentle", "giant", "giddy", "glad", "gold", "good", "grand", "gray",
+    "great", "green", "grumpy", "hairy", "handy", "happy", "hardy", "hasty", "heavy", "high",
+    "hollow", "honest", "hot", "huge", "humble", "hungry", "icy", "ideal", "idle", "inner",
+    "ivory", "jolly", "juicy", "jumbo", "just", "keen", "kind", "large", "late", "lazy", "lean",
+    "light", "lilac", "little", "lively", "local", "lofty", "lone", "long", "loud", "lovely",
+    "loyal", "lucky", "lunar", "magic", "major", "mellow", "merry", "mighty", "mild", "minor",
+    "misty", "modern", "moist", "moody", "mossy", "muddy", "murky", "narrow", "navy", "neat",
+    "new", "nice", "nimble", "noble", "noisy", "odd", "olive", "open", "orange", "outer", "pale",
+    "perky", "petite", "pink", "plain", "plump", "polar", "polite", "proud", "pure", "purple",
+    "quick", "quiet", "rapid", "rare", "ready", "real", "red", "regal", "rich", "rigid", "ripe",
+    "robust", "rocky", "rosy", "rough", "round", "royal", "rusty", "salty", "sandy", "shiny",
+    "short", "shy", "silent", "silky", "silly", "silver", "simple", "sleek", "sleepy", "slim",
+    "slow", "small", "smart", "smooth", "snowy", "soft", "solar", "solid", "sour", "spicy",
+    "steady", "stormy", "strong", "sunny", "super", "sweet", "swift", "tall", "tame", "tidy",
+    "tiny", "tough", "upper", "vast", "violet", "vivid", "warm", "wavy", "white", "wild", "windy",
+    "wise", "witty", "yellow", "young", "zany", "zesty",
+];
+
+const NOUNS: [&str; 256] = [
+    "acorn", "actor", "alarm", "album", "alley", "anchor", "angel", "ant", "apple", "apron",
+    "arrow", "atlas", "attic", "badge", "bagel", "ball", "banana", "banjo", "barn", "basket",
+    "beach", "beard", "bear", "beaver", "bee", "bell", "bench", "berry", "bird", "biscuit",
+    "blanket", "boat", "bonnet", "book", "boot", "bottle", "box", "bread", "brick", "bridge",
+    "broom", "bubble", "bucket", "bunny", "butter", "button", "cabin", "cactus", "cake", "camel",
+    "candle", "canoe", "canyon", "carrot", "castle", "cat", "cave", "cello", "chair", "cheese",
+    "cherry", "chess", "cloud", "clover", "coat", "cobra", "coconut", "comet", "cookie", "copper",
+    "corn", "cotton", "cow", "crab", "crane", "crayon", "cricket", "crown", "cup", "curtain",
+    "daisy", "desert", "dolphin", "donkey", "door", "dragon", "drum", "duck", "eagle", "earth",
+    "egg", "elbow", "engine", "falcon", "feather", "fence", "fern", "fiddle", "finch", "fire",
+    "fish", "flag", "flute", "forest", "fork", "fossil", "fox", "frog", "garden", "garlic",
+    "gecko", "giraffe", "glove", "goat", "goose", "grape", "guitar", "hammer", "harbor", "harp",
+    "hat", "helmet", "hill", "honey", "horse", "igloo", "island", "jacket", "jelly", "jungle",
+    "kettle", "key", "kite", "kitten", "koala", "ladder", "lake", "lamp", "lantern", "lemon",
+    "leopard", "letter", "lion", "lizard", "llama", "lobster", "lock", "magnet", "mango", "maple",
+    "marble", "meadow", "melon", "mirror", "mitten", "monkey", "moon", "moose", "mountain",
+    "muffin", "needle", "nest", "noodle", "oak", "ocean", "octopus", "olive", "onion", "orchid",
+    "otter", "owl", "paddle", "panda", "parrot", "peach", "peanut", "pear", "pebble", "pencil",
+    "penguin", "pepper", "piano", "pickle", "pigeon", "pillow", "pine", "pizza", "planet", "plum",
+    "pocket", "pony", "potato", "pretzel", "pumpkin", "puppy", "puzzle", "quilt", "rabbit",
+    "raccoon", "radio", "rain", "raven", "ribbon", "river", "robot", "rocket", "rose", "saddle",
+    "sail", "salmon", "sausage", "scarf", "seal", "shark", "sheep", "shell", "ship", "shoe",
+    "snail", "snake", "sock", "spider", "spoon", "squid", "star", "stone", "storm", "sugar", "sun",
+    "swan", "sweater", "table", "taco", "tiger", "toast", "tomato", "tower", "tractor", "train",
+    "tree", "trumpet", "tulip", "turtle", "unicorn", "valley", "violin", "wagon", "walrus",
+    "whale", "wheel", "whistle", "window", "wizard", "wolf", "yak", "zebra",
+];
+
+/// A code with the helper's `nameplate`.
+pub fn code(nameplate: u32) -> String {
+    let mut random = [0u8; 2];
+    os_random(&mut random);
+    format!(
+        "{}-{}-{}",
+        nameplate, ADJECTIVES[random[0] as usize], NOUNS[random[1] as usize]
+    )
+}
+
+/// Asks the helper for a nameplate and makes a code with it. Fails with the helper's reason if it
+/// refused, or None if it didn't answer (older helpers can't hand out nameplates).
+pub fn request(socket: &UdpSocket, helper: SocketAddr) -> Result<(u32, String), Option<String>> {
+    let token = env::var("QFT_HELPER_TOKEN").ok();
+    let mut buf = [0u8; 2048];
+    for _ in 0..3 {
+        // a new credential each time, helpers accept each one only once
+        let mut request = Message::new(Kind::Nameplate);
+        if let Some(token) = &token {
+            let credential = guard::credential(token.as_bytes(), b"nameplate", &[]);
+            request = request.with(protocol::CREDENTIAL, &credential);
+        }
+        // padded, because the helper doesn't answer with more than it got
+        let request = request.encode_padded(protocol::REGISTER_SIZE);
+        let _ = socket.send_to(&request, helper);
+        let deadline = unix_millis() + 1000;
+        while unix_millis() < deadline {
+            let Ok((l, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            let Some(message) = Message::decode(&buf[..l]).filter(|_| from == helper) else {
+                continue;
+            };
+            match message.kind {
+                Kind::Nameplate => {
+                    if let Some(nameplate) = message.get(protocol::NAMEPLATE) {
+                        if let Ok(nameplate) = nameplate.try_into() {
+                            let nameplate = u32::from_be_bytes(nameplate);
+                            return Ok((nameplate, code(nameplate)));
+                        }
+                    }
+                }
+                Kind::Error => {
+                    let text = message.get(protocol::MESSAGE).unwrap_or(b"no reason");
+                    return Err(Some(String::from_utf8_lossy(text).to_string()));
+                }
+                _ => (),
+            }
+        }
+    }
+    Err(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, net::Ipv4Addr, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn codes_are_number_and_two_words() {
+        for _ in 0..100 {
+            let code = code(7);
+            let parts: Vec<&str> = code.split('-').collect();
+            assert_eq!(parts.len(), 3, "{}", code);
+            assert_eq!(parts[0], "7");
+            assert!(ADJECTIVES.contains(&parts[1]), "{}", code);
+            assert!(NOUNS.contains(&parts[2]), "{}", code);
+        }
+    }
+
+    #[test]
+    fn words_are_distinct() {
+        for words in [ADJECTIVES, NOUNS] {
+            assert_eq!(words.iter().collect::<HashSet<_>>().len(), words.len());
+            assert!(words
+                .iter()
+                .all(|word| word.chars().all(|c| c.is_ascii_lowercase())));
+        }
+    }
+
+    /// A helper which answers every packet with `answer`, and the socket to ask it with.
+    fn helper(answer: Option<Message>) -> (UdpSocket, SocketAddr) {
+        let helper = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = helper.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            while let Ok((l, from)) = helper.recv_from(&mut buf) {
+                let request = Message::decode(&buf[..l]).unwrap();
+                assert!(matches!(request.kind, Kind::Nameplate));
+                if let Some(answer) = &answer {
+                    let _ = helper.send_to(&answer.encode(), from);
+                }
+            }
+        });
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        (socket, addr)
+    }
+
+    #[test]
+    fn requests_a_nameplate() {
+        let answer = Message::new(Kind::Nameplate).with(protocol::NAMEPLATE, &42u32.to_be_bytes());
+        let (socket, addr) = helper(Some(answer));
+        let (nameplate, code) = request(&socket, addr).unwrap();
+        assert_eq!(nameplate, 42);
+        assert!(code.starts_with("42-"));
+    }
+
+    #[test]
+    fn request_fails_with_the_reason() {
+        let answer = Message::new(Kind::Error).with(protocol::MESSAGE, b"no free nameplates");
+        let (socket, addr) = helper(Some(answer));
+        assert_eq!(
+            request(&socket, addr),
+            Err(Some("no free nameplates".to_owned()))
+        );
+        // older helpers don't answer
+        let (socket, addr) = helper(None);
+        assert_eq!(request(&socket, addr), Err(None));
+    }
+}